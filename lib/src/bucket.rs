@@ -2,11 +2,11 @@ use crate::index::{ChunkId, CHUNK_SIZE};
 
 pub(crate) type Packed = u16;
 
-#[cfg(target_feature = "avx512f")]
+/// Set on the length of a snapshot directory entry that holds a mask.
+pub(crate) const MASK_FLAG: u32 = 1 << 31;
+
+// switch to a mask once it is no larger than the equivalent vec
 const MAX_VEC_LEN: usize = CHUNK_SIZE as usize / (std::mem::size_of::<ChunkId>() * 8);
-#[cfg(not(target_feature = "avx512f"))]
-// prevent using masks without simd
-const MAX_VEC_LEN: usize = usize::MAX;
 
 #[derive(Clone)]
 pub(crate) enum Bucket {
@@ -72,7 +72,8 @@ impl Bucket {
             Self::Empty => {}
             Self::Array(array) => {
                 if let Some((index, _)) = array.iter().enumerate().find(|(_, i)| **i == id) {
-                    if index == 11 || array[index + 1] == 0 {
+                    let last = array.len() - 1;
+                    if index == last || array[index + 1] == 0 {
                         array[index] = 0;
                         if index == 0 {
                             *self = Self::Empty;
@@ -80,7 +81,7 @@ impl Bucket {
                         return;
                     }
                     array.copy_within(index + 1.., index);
-                    array[11] = 0;
+                    array[last] = 0;
                 }
             }
             Self::Vec(vec) => {
@@ -114,4 +115,124 @@ impl Bucket {
             }
         }
     }
+
+    /// Appends the contents to `pool` and returns the `[offset, len]` directory entry.
+    pub(crate) fn encode(&self, pool: &mut Vec<Packed>) -> [u32; 2] {
+        let offset = pool.len() as u32;
+        let len = match self {
+            Self::Empty => 0,
+            Self::Array(array) => {
+                pool.push(array[0]);
+                pool.extend(array[1..].iter().take_while(|&&id| id != 0));
+                pool.len() as u32 - offset
+            }
+            Self::Vec(vec) => {
+                pool.extend_from_slice(vec);
+                vec.len() as u32
+            }
+            Self::Mask(mask) => {
+                pool.extend_from_slice(mask);
+                mask.len() as u32 | MASK_FLAG
+            }
+        };
+        [offset, len]
+    }
+
+    /// Rebuilds a bucket from a directory entry, rejecting ids outside `0..total`.
+    pub(crate) fn decode(entry: [u32; 2], pool: &[Packed], total: usize) -> Option<Self> {
+        let bucket = match BucketRef::decode(entry, pool, total)? {
+            BucketRef::Ids([]) => Self::Empty,
+            BucketRef::Ids(ids) if ids.len() <= 15 => {
+                let mut array = [0; 15];
+                array[..ids.len()].copy_from_slice(ids);
+                Self::Array(array)
+            }
+            BucketRef::Ids(ids) => Self::Vec(ids.to_vec()),
+            BucketRef::Mask(mask) => Self::Mask(mask.to_vec()),
+        };
+        Some(bucket)
+    }
+
+    pub(crate) fn as_ref(&self) -> BucketRef<'_> {
+        match self {
+            Self::Empty => BucketRef::Ids(&[]),
+            Self::Array(array) => {
+                let len = 1 + array[1..].iter().take_while(|&&id| id != 0).count();
+                BucketRef::Ids(&array[..len])
+            }
+            Self::Vec(vec) => BucketRef::Ids(vec),
+            Self::Mask(mask) => BucketRef::Mask(mask),
+        }
+    }
+}
+
+/// A borrowed bucket, either from memory or from a mapped snapshot.
+#[derive(Clone, Copy)]
+pub(crate) enum BucketRef<'a> {
+    Ids(&'a [ChunkId]),
+    Mask(&'a [Packed]),
+}
+
+impl<'a> BucketRef<'a> {
+    pub(crate) fn ids(self) -> impl Iterator<Item = ChunkId> + 'a {
+        let (ids, mask) = match self {
+            Self::Ids(ids) => (ids, &[][..]),
+            Self::Mask(mask) => (&[][..], mask),
+        };
+        let masked = mask.iter().enumerate().flat_map(|(index, &m)| {
+            let index = index as ChunkId * Packed::BITS as ChunkId;
+            (0..Packed::BITS as ChunkId)
+                .filter(move |offset| m & (1 << offset) != 0)
+                .map(move |offset| index + offset)
+        });
+        ids.iter().copied().chain(masked)
+    }
+
+    /// Resolves a directory entry that has already been checked by [`BucketRef::decode`].
+    pub(crate) fn from_entry([offset, len]: [u32; 2], pool: &'a [Packed]) -> Self {
+        let values = &pool[offset as usize..][..(len & !MASK_FLAG) as usize];
+        if len & MASK_FLAG != 0 {
+            Self::Mask(values)
+        } else {
+            Self::Ids(values)
+        }
+    }
+
+    /// Resolves a directory entry, rejecting ids outside `0..total`.
+    pub(crate) fn decode(
+        [offset, len]: [u32; 2],
+        pool: &'a [Packed],
+        total: usize,
+    ) -> Option<Self> {
+        let values = pool
+            .get(offset as usize..)?
+            .get(..(len & !MASK_FLAG) as usize)?;
+        if len & MASK_FLAG != 0 {
+            if values.len() > total.div_ceil(Packed::BITS as usize) {
+                return None;
+            }
+            return Some(Self::Mask(values));
+        }
+        if values.iter().any(|&id| id as usize >= total) {
+            return None;
+        }
+        Some(Self::Ids(values))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remove() {
+        let mut bucket = Bucket::new();
+        for id in 0..15 {
+            bucket.append(id);
+        }
+        bucket.remove(14);
+        bucket.remove(3);
+        let ids: Vec<_> = bucket.as_ref().ids().collect();
+        assert_eq!(ids, [0, 1, 2, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13]);
+    }
 }
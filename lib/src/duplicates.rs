@@ -0,0 +1,272 @@
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+#[cfg(feature = "multi-thread")]
+use rayon::prelude::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
+
+use crate::{index::CHUNK_SIZE, QueryOptions, Signature, DB};
+
+/// Two images that score above the threshold against each other, with `a < b`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Duplicate {
+    pub a: i64,
+    pub b: i64,
+    /// The better of the two scores, `a` against `b` and `b` against `a`.
+    pub score: f32,
+}
+
+/// A search for every pair of images that score at least a threshold against each
+/// other, run a batch of slots at a time so that the index is only borrowed for one
+/// batch and can change in between.
+///
+/// Images added in between are scanned too, since new slots come last. The index
+/// must not be compacted in between, since that moves images to other slots.
+pub struct DuplicateSearch {
+    options: QueryOptions,
+    next: usize,
+    best: HashMap<(i64, i64), f32>,
+    /// The signatures of the chunk being scanned, by chunk number.
+    signatures: Option<(usize, Vec<Signature>)>,
+}
+
+impl DuplicateSearch {
+    pub fn new(threshold: f32) -> Self {
+        Self::with_options(threshold, QueryOptions::default())
+    }
+
+    /// Like [`DuplicateSearch::new`], scoring with the weights and mode of `options`
+    /// and only pairing images that match its filter. Its `min_score` is replaced
+    /// by `threshold`.
+    pub fn with_options(threshold: f32, options: QueryOptions) -> Self {
+        Self {
+            options: QueryOptions {
+                min_score: Some(threshold),
+                ..options
+            },
+            next: 0,
+            best: HashMap::new(),
+            signatures: None,
+        }
+    }
+
+    /// The number of slots scanned so far.
+    pub fn done(&self) -> usize {
+        self.next
+    }
+
+    /// Scans up to `batch` more slots of `db`, calling `progress` with the number of
+    /// slots done so far and the total number of slots.
+    ///
+    /// A batch never spans two chunks. The signatures of a chunk are rebuilt from its
+    /// buckets once, and again only if a batch reaches slots added since.
+    /// Returns `false` once every slot is done.
+    pub fn step(
+        &mut self,
+        db: &DB,
+        batch: usize,
+        progress: &(impl Fn(usize, usize) + Sync),
+    ) -> bool {
+        let (index_to_id, chunks) = db.chunks();
+        let total = index_to_id.len();
+        let chunk_number = self.next / CHUNK_SIZE as usize;
+        let Some(chunk) = chunks.get(chunk_number) else {
+            return false;
+        };
+        let start = self.next - chunk.offset as usize;
+        let end = (start + batch.max(1)).min(chunk.avgl_y.len());
+        if start >= end {
+            return false;
+        }
+        let signatures = match &mut self.signatures {
+            Some((number, signatures)) if *number == chunk_number && signatures.len() >= end => {
+                signatures
+            }
+            cached => &cached.insert((chunk_number, chunk.signatures())).1,
+        };
+        let options = &self.options;
+        let filtered = !options.filter.is_empty();
+        let done = AtomicUsize::new(self.next);
+        let find = |(slot, sig): (usize, &Signature)| {
+            let index = chunk.offset + (start + slot) as u32;
+            let mut found = Vec::new();
+            let matches = !filtered || options.filter.matches(&chunk.metadata(start + slot));
+            if !chunk.is_deleted(index) && matches {
+                let id = index_to_id[index as usize];
+                for (score, other) in chunks.iter().flat_map(|c| c.above(sig, options)) {
+                    let other = index_to_id[other as usize];
+                    if other != id {
+                        found.push((id.min(other), id.max(other), score));
+                    }
+                }
+            }
+            progress(done.fetch_add(1, Ordering::Relaxed) + 1, total);
+            found
+        };
+        let signatures = &signatures[start..end];
+        #[cfg(feature = "multi-thread")]
+        let found: Vec<_> = signatures
+            .par_iter()
+            .enumerate()
+            .flat_map_iter(find)
+            .collect();
+        #[cfg(not(feature = "multi-thread"))]
+        let found: Vec<_> = signatures.iter().enumerate().flat_map(find).collect();
+        for (a, b, score) in found {
+            let best = self.best.entry((a, b)).or_insert(score);
+            *best = best.max(score);
+        }
+        self.next = chunk.offset as usize + end;
+        true
+    }
+
+    /// The pairs found so far, sorted by `a` and then `b`.
+    pub fn finish(self) -> Vec<Duplicate> {
+        let mut duplicates: Vec<_> = self
+            .best
+            .into_iter()
+            .map(|((a, b), score)| Duplicate { a, b, score })
+            .collect();
+        duplicates.sort_unstable_by_key(|duplicate| (duplicate.a, duplicate.b));
+        duplicates
+    }
+}
+
+/// Groups images linked by any chain of duplicate pairs.
+///
+/// Clusters are sorted by their smallest id, and the ids in each cluster are sorted.
+pub fn clusters(duplicates: &[Duplicate]) -> Vec<Vec<i64>> {
+    let mut parents: HashMap<i64, i64> = HashMap::new();
+    let mut sizes: HashMap<i64, usize> = HashMap::new();
+    fn root(parents: &mut HashMap<i64, i64>, id: i64) -> i64 {
+        let mut root = id;
+        loop {
+            let parent = *parents.entry(root).or_insert(root);
+            if parent == root {
+                break;
+            }
+            root = parent;
+        }
+        // Points everything on the path straight at the root.
+        let mut id = id;
+        while id != root {
+            id = std::mem::replace(parents.get_mut(&id).unwrap(), root);
+        }
+        root
+    }
+    for duplicate in duplicates {
+        let a = root(&mut parents, duplicate.a);
+        let b = root(&mut parents, duplicate.b);
+        if a == b {
+            continue;
+        }
+        // Hangs the smaller tree under the larger one, to keep paths short.
+        let size_a = *sizes.get(&a).unwrap_or(&1);
+        let size_b = *sizes.get(&b).unwrap_or(&1);
+        let (small, large) = if size_a < size_b { (a, b) } else { (b, a) };
+        parents.insert(small, large);
+        sizes.insert(large, size_a + size_b);
+    }
+
+    let ids: Vec<i64> = parents.keys().copied().collect();
+    let mut clusters: HashMap<i64, Vec<i64>> = HashMap::new();
+    for id in ids {
+        let root = root(&mut parents, id);
+        clusters.entry(root).or_default().push(id);
+    }
+    let mut clusters: Vec<_> = clusters.into_values().collect();
+    for cluster in &mut clusters {
+        cluster.sort_unstable();
+    }
+    clusters.sort_unstable();
+    clusters
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing::image, QueryMode};
+
+    #[test]
+    fn find_duplicates() {
+        // 1 and 2 are copies and 3 is close to both, 4 and 5 are copies of something
+        // else, and 6 was a copy of 1 until it was deleted.
+        let mut db = DB::new([
+            image(1, 1),
+            image(2, 1),
+            image(3, 2),
+            image(4, 1000),
+            image(5, 1000),
+            image(6, 1),
+        ]);
+        db.delete(image(6, 1)).unwrap();
+
+        let done = AtomicUsize::new(0);
+        let duplicates = db.find_duplicates_with_progress(90., |_, total| {
+            assert_eq!(total, 6);
+            done.fetch_add(1, Ordering::Relaxed);
+        });
+        assert_eq!(done.into_inner(), 6);
+        let pairs: Vec<_> = duplicates.iter().map(|d| (d.a, d.b)).collect();
+        assert_eq!(pairs, [(1, 2), (1, 3), (2, 3), (4, 5)]);
+        assert!((duplicates[0].score - 100.).abs() < 0.01);
+        assert!(duplicates[1].score < 99.);
+        assert_eq!(clusters(&duplicates), [vec![1, 2, 3], vec![4, 5]]);
+
+        let pairs: Vec<_> = db.find_duplicates(99.).iter().map(|d| (d.a, d.b)).collect();
+        assert_eq!(pairs, [(1, 2), (4, 5)]);
+    }
+
+    #[test]
+    fn search_in_steps() {
+        let mut db = DB::new([image(1, 1), image(2, 1000), image(3, 2000)]);
+        let mut search = DuplicateSearch::new(90.);
+        assert!(search.step(&db, 2, &|_, _| ()));
+        assert_eq!(search.done(), 2);
+
+        // The index can change between steps, and new images are scanned too.
+        db.insert(image(4, 2000)).unwrap();
+        db.insert(image(5, 1000)).unwrap();
+        while search.step(&db, 2, &|_, _| ()) {}
+        assert_eq!(search.done(), 5);
+        let pairs: Vec<_> = search.finish().iter().map(|d| (d.a, d.b)).collect();
+        assert_eq!(pairs, [(2, 5), (3, 4)]);
+    }
+
+    #[test]
+    fn grayscale() {
+        // The luminance of image 1, with other chroma.
+        let mut gray = image(2, 1);
+        gray.avgl = (0.5, 0.3, -0.3);
+        gray.sig[40..].iter_mut().for_each(|coef| *coef += 3000);
+        let db = DB::new([image(1, 1), gray]);
+        let pairs = |options: QueryOptions| {
+            let mut search = DuplicateSearch::with_options(90., options);
+            while search.step(&db, 10, &|_, _| ()) {}
+            let pairs: Vec<_> = search.finish().iter().map(|d| (d.a, d.b)).collect();
+            pairs
+        };
+        assert_eq!(pairs(QueryOptions::default()), []);
+        let grayscale = QueryOptions {
+            mode: QueryMode::Grayscale,
+            ..Default::default()
+        };
+        assert_eq!(pairs(grayscale), [(1, 2)]);
+    }
+
+    #[test]
+    fn long_chain() {
+        // Deep enough to overflow the stack with a recursive find.
+        let duplicates: Vec<_> = (0..1_000_000)
+            .map(|a| Duplicate {
+                a,
+                b: a + 1,
+                score: 95.,
+            })
+            .collect();
+        let clusters = clusters(&duplicates);
+        assert_eq!(clusters.len(), 1);
+        assert_eq!(clusters[0], (0..=1_000_000).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,246 @@
+use std::{io::Cursor, str::FromStr};
+
+use image::{
+    codecs::{gif::GifDecoder, png::PngDecoder, webp::WebPDecoder},
+    AnimationDecoder, DynamicImage, Frames, GenericImageView, GrayImage, ImageFormat, ImageResult,
+    RgbImage,
+};
+use jpeg_decoder::PixelFormat;
+
+/// The smallest side [`decode_frames_scaled`] is usually asked to keep: twice the 128
+/// pixels that get hashed, so that resampling still averages several pixels.
+pub const MIN_DECODE_SIZE: u32 = 256;
+
+/// Which frames of an animated GIF, APNG or WebP get a signature.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FrameSelection {
+    #[default]
+    First,
+    Middle,
+    /// Up to `n` evenly spaced frames, starting with the first one.
+    Spaced(usize),
+}
+
+impl FrameSelection {
+    fn indexes(self, total: usize) -> Vec<usize> {
+        match self {
+            _ if total == 0 => Vec::new(),
+            Self::First => vec![0],
+            Self::Middle => vec![total / 2],
+            Self::Spaced(n) => {
+                let n = n.clamp(1, total);
+                (0..n).map(|i| i * total / n).collect()
+            }
+        }
+    }
+}
+
+impl FromStr for FrameSelection {
+    type Err = ();
+
+    /// Parses `first`, `middle` or a frame count such as `4`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "first" => Ok(Self::First),
+            "middle" => Ok(Self::Middle),
+            _ => match s.parse() {
+                Ok(0) | Err(_) => Err(()),
+                Ok(n) => Ok(Self::Spaced(n)),
+            },
+        }
+    }
+}
+
+fn animation(bytes: &[u8]) -> ImageResult<Option<Frames<'_>>> {
+    let frames = match image::guess_format(bytes)? {
+        ImageFormat::Gif => GifDecoder::new(Cursor::new(bytes))?.into_frames(),
+        ImageFormat::Png => {
+            let decoder = PngDecoder::new(Cursor::new(bytes))?;
+            if !decoder.is_apng()? {
+                return Ok(None);
+            }
+            decoder.apng()?.into_frames()
+        }
+        ImageFormat::WebP => {
+            let decoder = WebPDecoder::new(Cursor::new(bytes))?;
+            if !decoder.has_animation() {
+                return Ok(None);
+            }
+            decoder.into_frames()
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(frames))
+}
+
+/// Decodes the selected frames of an image, in order.
+///
+/// Still images and formats without animation support always yield a single frame.
+pub fn decode_frames(bytes: &[u8], selection: FrameSelection) -> ImageResult<Vec<DynamicImage>> {
+    if selection == FrameSelection::First {
+        return Ok(vec![image::load_from_memory(bytes)?]);
+    }
+    let Some(frames) = animation(bytes)? else {
+        return Ok(vec![image::load_from_memory(bytes)?]);
+    };
+    // Count first so that only the selected frames are kept in memory.
+    let total = frames.count();
+    let mut indexes = selection.indexes(total).into_iter().peekable();
+    let mut selected = Vec::new();
+    for (i, frame) in animation(bytes)?.into_iter().flatten().enumerate() {
+        if indexes.peek() != Some(&i) {
+            continue;
+        }
+        indexes.next();
+        selected.push(DynamicImage::ImageRgba8(frame?.into_buffer()));
+        if indexes.peek().is_none() {
+            break;
+        }
+    }
+    if selected.is_empty() {
+        return Ok(vec![image::load_from_memory(bytes)?]);
+    }
+    Ok(selected)
+}
+
+/// Decodes the selected frames like [`decode_frames`], but lets JPEGs be scaled down
+/// by 1/2, 1/4 or 1/8 while decoding, in the DCT domain, as long as both sides stay at
+/// least `min_size`. Skipping most of the pixels makes large photos much faster to hash.
+///
+/// With [`MIN_DECODE_SIZE`], the 128x128 image that gets hashed stays within 4 levels
+/// per channel of the one from a full decode, and photos score above 99 against their
+/// fully decoded signatures.
+///
+/// Also returns the full size of the image, to map positions in the frames back to it.
+pub fn decode_frames_scaled(
+    bytes: &[u8],
+    selection: FrameSelection,
+    min_size: u32,
+) -> ImageResult<(Vec<DynamicImage>, (u32, u32))> {
+    if let Some(scaled) = decode_jpeg_scaled(bytes, min_size) {
+        return Ok(scaled);
+    }
+    let frames = decode_frames(bytes, selection)?;
+    let size = frames.first().map_or((0, 0), |frame| frame.dimensions());
+    Ok((frames, size))
+}
+
+/// Returns `None` for anything but 8-bit grayscale and RGB JPEGs too small to scale,
+/// which are left to [`image`] to decode or report errors for.
+fn decode_jpeg_scaled(bytes: &[u8], min_size: u32) -> Option<(Vec<DynamicImage>, (u32, u32))> {
+    if image::guess_format(bytes).ok()? != ImageFormat::Jpeg {
+        return None;
+    }
+    let mut decoder = jpeg_decoder::Decoder::new(bytes);
+    decoder.read_info().ok()?;
+    let info = decoder.info()?;
+    if !matches!(info.pixel_format, PixelFormat::L8 | PixelFormat::RGB24) {
+        return None;
+    }
+    // The decoder picks the smallest scale that keeps either side at the requested
+    // size, so only the shorter side is requested.
+    let min_size = min_size.min(u16::MAX as u32) as u16;
+    let (width, height) = if info.width <= info.height {
+        decoder.scale(min_size, u16::MAX).ok()?
+    } else {
+        decoder.scale(u16::MAX, min_size).ok()?
+    };
+    if (width, height) == (info.width, info.height) {
+        return None;
+    }
+    let pixels = decoder.decode().ok()?;
+    let (width, height) = (width as u32, height as u32);
+    let image = match info.pixel_format {
+        PixelFormat::L8 => DynamicImage::ImageLuma8(GrayImage::from_raw(width, height, pixels)?),
+        _ => DynamicImage::ImageRgb8(RgbImage::from_raw(width, height, pixels)?),
+    };
+    Some((vec![image], (info.width as u32, info.height as u32)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ImageData, Libgd, Metadata, Resampler, Signature, DB};
+
+    #[test]
+    fn decode_frames() {
+        use image::{codecs::gif::GifEncoder, Frame, Rgba, RgbaImage};
+
+        let mut bytes = Vec::new();
+        {
+            let mut encoder = GifEncoder::new(&mut bytes);
+            for i in 0..5 {
+                let img = RgbaImage::from_pixel(8, 8, Rgba([i * 50, 0, 0, 255]));
+                encoder.encode_frame(Frame::new(img)).unwrap();
+            }
+        }
+        let reds = |selection| -> Vec<u8> {
+            let frames = super::decode_frames(&bytes, selection).unwrap();
+            frames
+                .iter()
+                .map(|f| f.to_rgba8().get_pixel(0, 0)[0])
+                .collect()
+        };
+        assert_eq!(reds(FrameSelection::First), [0]);
+        assert_eq!(reds(FrameSelection::Middle), [100]);
+        assert_eq!(reds(FrameSelection::Spaced(2)), [0, 100]);
+        assert_eq!(reds(FrameSelection::Spaced(9)), [0, 50, 100, 150, 200]);
+        assert_eq!("3".parse(), Ok(FrameSelection::Spaced(3)));
+        assert_eq!("0".parse::<FrameSelection>(), Err(()));
+    }
+
+    #[test]
+    fn scaled_jpeg() {
+        use image::codecs::jpeg::JpegEncoder;
+
+        for (width, height, scaled) in [(1200, 900, (600, 450)), (2048, 2304, (256, 288))] {
+            // Smooth gradients with some grain, like a photo.
+            let img = image::RgbImage::from_fn(width, height, |x, y| {
+                let grain = (x * 7 + y * 13) % 24;
+                let r = x * 200 / width + grain;
+                let g = y * 200 / height + grain;
+                let (dx, dy) = (x.abs_diff(width / 3), y.abs_diff(height / 2));
+                let b = 255 - ((dx * dx + dy * dy) * 255 / (width * width + height * height)) * 2;
+                image::Rgb([r as u8, g as u8, b as u8])
+            });
+            let mut bytes = Vec::new();
+            let mut encoder = JpegEncoder::new_with_quality(&mut bytes, 90);
+            encoder.encode_image(&img).unwrap();
+
+            let full = super::decode_frames(&bytes, FrameSelection::First).unwrap();
+            let (frames, size) =
+                decode_frames_scaled(&bytes, FrameSelection::First, MIN_DECODE_SIZE).unwrap();
+            assert_eq!(size, (width, height));
+            assert_eq!((frames[0].width(), frames[0].height()), scaled);
+
+            let reference = Libgd.resize(&full[0]);
+            let fast = Libgd.resize(&frames[0]);
+            let max_diff = (reference.as_raw().iter().zip(fast.as_raw()))
+                .map(|(a, b)| a.abs_diff(*b))
+                .max();
+            let sig = Signature::from_image(&full[0]);
+            let db = DB::new([ImageData {
+                id: 1,
+                avgl: sig.avgl,
+                sig: sig.sig,
+                metadata: Metadata::default(),
+            }]);
+            let result = db.query(&Signature::from_image(&frames[0]), 1);
+            assert!(max_diff <= Some(4), "{max_diff:?}");
+            assert!(result[0].0 > 99., "{}", result[0].0);
+        }
+
+        // Anything else decodes at full size.
+        let mut bytes = Vec::new();
+        let img = image::DynamicImage::new_rgb8(1200, 900);
+        img.write_to(
+            &mut std::io::Cursor::new(&mut bytes),
+            image::ImageFormat::Png,
+        )
+        .unwrap();
+        let (frames, size) =
+            decode_frames_scaled(&bytes, FrameSelection::First, MIN_DECODE_SIZE).unwrap();
+        assert_eq!(size, (1200, 900));
+        assert_eq!(frames[0], img);
+    }
+}
@@ -1,11 +1,136 @@
-use std::{fmt::Display, str::FromStr};
+use std::{fmt::Display, fs, io, io::Read, ops::Range, path::Path, str::FromStr};
 
-use image::{DynamicImage, GenericImageView, ImageBuffer, Rgba};
+use image::{DynamicImage, GenericImageView, ImageBuffer, ImageError, Rgba};
+
+use crate::{
+    frames::{decode_frames_scaled, FrameSelection, MIN_DECODE_SIZE},
+    resample::{Resampler, Resampling},
+};
 
 const NUM_PIXELS: usize = 128;
 const NUM_PIXELS_SQUARED: usize = NUM_PIXELS * NUM_PIXELS;
 const NUM_COEFS: usize = 40;
 
+/// How transparent pixels are turned into colour before hashing.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AlphaMode {
+    /// Use the colour under the alpha channel, with fully transparent areas
+    /// turning black. This is what the original iqdb does.
+    #[default]
+    Ignore,
+    /// Composite onto a solid background colour.
+    Background([u8; 3]),
+}
+
+impl AlphaMode {
+    pub const WHITE: Self = Self::Background([255, 255, 255]);
+    pub const BLACK: Self = Self::Background([0, 0, 0]);
+
+    fn composite(self, Rgba([r, g, b, a]): Rgba<u8>) -> [f64; 3] {
+        match self {
+            Self::Ignore => [r as f64, g as f64, b as f64],
+            Self::Background(background) => {
+                let a = a as f64 / 255.;
+                let mut pixel = [r, g, b].map(|c| c as f64);
+                for (c, bg) in pixel.iter_mut().zip(background) {
+                    *c = *c * a + bg as f64 * (1. - a);
+                }
+                pixel
+            }
+        }
+    }
+}
+
+impl FromStr for AlphaMode {
+    type Err = ();
+
+    /// Parses `ignore`, `white`, `black` or a hex colour such as `#ff8800`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ignore" => return Ok(Self::Ignore),
+            "white" => return Ok(Self::WHITE),
+            "black" => return Ok(Self::BLACK),
+            _ => {}
+        }
+        let hex = s.strip_prefix('#').unwrap_or(s);
+        if hex.len() != 6 || !hex.is_ascii() {
+            return Err(());
+        }
+        let mut color = [0; 3];
+        for (i, c) in color.iter_mut().enumerate() {
+            *c = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| ())?;
+        }
+        Ok(Self::Background(color))
+    }
+}
+
+/// Options for [`Signature::from_image_with`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SignatureOptions {
+    pub alpha: AlphaMode,
+    /// Trims near-uniform borders before hashing, see [`trim_bounds`].
+    pub trim: Option<u8>,
+    pub resampling: Resampling,
+}
+
+/// Why an image or a hash couldn't be turned into a [`Signature`].
+#[derive(Debug)]
+pub enum SignatureError {
+    /// The image is corrupt or truncated.
+    Decode(ImageError),
+    /// The format isn't recognized, or isn't supported by its decoder.
+    UnsupportedFormat(ImageError),
+    /// Decoding would take more memory than the decoder allows.
+    TooLarge,
+    /// The image has no pixels.
+    ZeroSized,
+    /// Reading the image failed.
+    Io(io::Error),
+    /// A hash doesn't have 528 hex digits after the optional `iqdb_` prefix.
+    BadHashLength { expected: usize, found: usize },
+    /// A hash contains something other than hex digits.
+    BadHex,
+    /// A hash decodes to coefficients no image can have, see [`Signature::is_valid`].
+    InvalidCoefficients,
+}
+
+impl From<ImageError> for SignatureError {
+    fn from(error: ImageError) -> Self {
+        match error {
+            ImageError::Unsupported(_) => Self::UnsupportedFormat(error),
+            ImageError::Limits(_) => Self::TooLarge,
+            error => Self::Decode(error),
+        }
+    }
+}
+
+impl Display for SignatureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Decode(error) => write!(f, "failed to decode image: {error}"),
+            Self::UnsupportedFormat(error) => write!(f, "unsupported image format: {error}"),
+            Self::TooLarge => write!(f, "image is too large to decode"),
+            Self::ZeroSized => write!(f, "image has no pixels"),
+            Self::Io(error) => write!(f, "failed to read image: {error}"),
+            Self::BadHashLength { expected, found } => {
+                write!(f, "hash has {found} hex digits, expected {expected}")
+            }
+            Self::BadHex => write!(f, "hash contains invalid hex digits"),
+            Self::InvalidCoefficients => write!(f, "hash has invalid coefficients"),
+        }
+    }
+}
+
+impl std::error::Error for SignatureError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Decode(error) | Self::UnsupportedFormat(error) => Some(error),
+            Self::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct Signature {
     pub avgl: (f64, f64, f64),
@@ -13,30 +138,42 @@ pub struct Signature {
 }
 
 impl FromStr for Signature {
-    type Err = ();
+    type Err = SignatureError;
 
     fn from_str(mut s: &str) -> Result<Self, Self::Err> {
         s = s.strip_prefix("iqdb_").unwrap_or(s);
         let mut avgl = [0., 0., 0.];
         let mut sig = vec![0; NUM_COEFS * 3];
-        if ((avgl.len() * 16) + (sig.len() * 4)) != s.len() {
-            return Err(());
+        let expected = (avgl.len() * 16) + (sig.len() * 4);
+        if expected != s.len() {
+            return Err(SignatureError::BadHashLength {
+                expected,
+                found: s.len(),
+            });
+        }
+
+        if !s.is_ascii() {
+            return Err(SignatureError::BadHex);
         }
 
         for f in &mut avgl {
-            let bits = u64::from_str_radix(&s[0..16], 16).unwrap();
+            let bits = u64::from_str_radix(&s[0..16], 16).map_err(|_| SignatureError::BadHex)?;
             *f = f64::from_bits(bits);
             s = &s[16..];
         }
         for i in &mut sig {
-            let bits = u16::from_str_radix(&s[0..4], 16).unwrap();
-            *i = unsafe { std::mem::transmute::<u16, i16>(bits) };
+            let bits = u16::from_str_radix(&s[0..4], 16).map_err(|_| SignatureError::BadHex)?;
+            *i = bits as i16;
             s = &s[4..];
         }
-        Ok(Self {
+        let sig = Self {
             avgl: (avgl[0], avgl[1], avgl[2]),
             sig,
-        })
+        };
+        if !sig.is_valid() {
+            return Err(SignatureError::InvalidCoefficients);
+        }
+        Ok(sig)
     }
 }
 
@@ -47,7 +184,7 @@ impl Display for Signature {
         write!(f, "{:016x}", self.avgl.1.to_bits())?;
         write!(f, "{:016x}", self.avgl.2.to_bits())?;
         for &i in &self.sig {
-            write!(f, "{:04x}", unsafe { std::mem::transmute::<i16, u16>(i) })?;
+            write!(f, "{:04x}", i as u16)?;
         }
         Ok(())
     }
@@ -65,6 +202,21 @@ fn rgb_to_yiq(r: &mut [f64], g: &mut [f64], b: &mut [f64]) {
 }
 
 impl Signature {
+    /// Whether this has 40 distinct coefficients per color, all within the
+    /// 128x128 transform, and finite averages. Only valid signatures can be indexed.
+    pub fn is_valid(&self) -> bool {
+        let (y, i, q) = self.avgl;
+        if !(y.is_finite() && i.is_finite() && q.is_finite()) || self.sig.len() != NUM_COEFS * 3 {
+            return false;
+        }
+        self.sig.chunks(NUM_COEFS).all(|coefs| {
+            let mut coefs = coefs.to_vec();
+            coefs.sort_unstable();
+            let in_range = |&c: &i16| c != 0 && (c.unsigned_abs() as usize) < NUM_PIXELS_SQUARED;
+            coefs.iter().all(in_range) && coefs.windows(2).all(|w| w[0] != w[1])
+        })
+    }
+
     #[allow(clippy::approx_constant)]
     fn haar_2d(a: &mut [f64]) {
         let mut i = 0;
@@ -222,8 +374,76 @@ impl Signature {
     }
 
     pub fn from_image(img: &DynamicImage) -> Signature {
-        let img = resized(img);
+        Self::from_image_with(img, &SignatureOptions::default())
+    }
+
+    pub fn from_image_with(img: &DynamicImage, options: &SignatureOptions) -> Signature {
+        Self::from_image_trimmed(img, options).0
+    }
+
+    /// Decodes and hashes the first frame of an encoded image. Large JPEGs are scaled
+    /// down while decoding, see [`crate::decode_frames_scaled`].
+    pub fn from_bytes(
+        bytes: &[u8],
+        options: &SignatureOptions,
+    ) -> Result<Signature, SignatureError> {
+        let (frames, (width, height)) =
+            decode_frames_scaled(bytes, FrameSelection::First, MIN_DECODE_SIZE)?;
+        match frames.first() {
+            Some(img) if width > 0 && height > 0 => Ok(Self::from_image_with(img, options)),
+            _ => Err(SignatureError::ZeroSized),
+        }
+    }
+
+    /// Reads an encoded image to the end and hashes it like [`Signature::from_bytes`].
+    pub fn from_reader(
+        mut reader: impl Read,
+        options: &SignatureOptions,
+    ) -> Result<Signature, SignatureError> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).map_err(SignatureError::Io)?;
+        Self::from_bytes(&bytes, options)
+    }
 
+    /// Reads an image file and hashes it like [`Signature::from_bytes`].
+    pub fn from_path(
+        path: impl AsRef<Path>,
+        options: &SignatureOptions,
+    ) -> Result<Signature, SignatureError> {
+        let bytes = fs::read(path).map_err(SignatureError::Io)?;
+        Self::from_bytes(&bytes, options)
+    }
+
+    /// Hashes an image like [`Signature::from_image_with`], also returning the
+    /// `(x, y, width, height)` of the part that was hashed after trimming borders.
+    pub fn from_image_trimmed(
+        img: &DynamicImage,
+        options: &SignatureOptions,
+    ) -> (Signature, (u32, u32, u32, u32)) {
+        Self::from_image_resampled(img, options, &options.resampling)
+    }
+
+    /// Hashes an image like [`Signature::from_image_trimmed`], but scales it with
+    /// `resampler` instead of [`SignatureOptions::resampling`].
+    pub fn from_image_resampled(
+        img: &DynamicImage,
+        options: &SignatureOptions,
+        resampler: &dyn Resampler,
+    ) -> (Signature, (u32, u32, u32, u32)) {
+        let alpha = options.alpha;
+        let Some(tolerance) = options.trim else {
+            let bounds = (0, 0, img.width(), img.height());
+            return (Self::hash(&resampler.resize(img), alpha), bounds);
+        };
+        let bounds @ (x, y, width, height) = trim_bounds(img, tolerance);
+        if (width, height) == img.dimensions() {
+            return (Self::hash(&resampler.resize(img), alpha), bounds);
+        }
+        let img = resampler.resize(&img.crop_imm(x, y, width, height));
+        (Self::hash(&img, alpha), bounds)
+    }
+
+    fn hash(img: &ImageBuffer<Rgba<u8>, Vec<u8>>, alpha: AlphaMode) -> Signature {
         let mut a = vec![0.0; NUM_PIXELS_SQUARED];
         let mut b = vec![0.0; NUM_PIXELS_SQUARED];
         let mut c = vec![0.0; NUM_PIXELS_SQUARED];
@@ -231,15 +451,11 @@ impl Signature {
         for y in 0..NUM_PIXELS {
             for x in 0..NUM_PIXELS {
                 let index = x + y * NUM_PIXELS;
-                if let Some(&Rgba(pixel)) = img.get_pixel_checked(x as u32, y as u32) {
-                    // TODO: handle alpha channel
-                    // let pixel = pixel.map(|c| c as f64 / 255.);
-                    // a[index] = ((1. - pixel[3]) + (pixel[3] * pixel[0])) * 255.;
-                    // b[index] = ((1. - pixel[3]) + (pixel[3] * pixel[1])) * 255.;
-                    // c[index] = ((1. - pixel[3]) + (pixel[3] * pixel[2])) * 255.;
-                    a[index] = pixel[0] as f64;
-                    b[index] = pixel[1] as f64;
-                    c[index] = pixel[2] as f64;
+                if let Some(&pixel) = img.get_pixel_checked(x as u32, y as u32) {
+                    let [red, green, blue] = alpha.composite(pixel);
+                    a[index] = red;
+                    b[index] = green;
+                    c[index] = blue;
                 }
             }
         }
@@ -249,14 +465,74 @@ impl Signature {
     }
 }
 
+/// The `(x, y, width, height)` of an image without its borders.
+///
+/// A side is trimmed while its outermost row or column stays within `tolerance` of its
+/// first pixel in every channel, including alpha. Sides are trimmed in turn until none
+/// changes, so that borders of different colours can be nested. Uniform images are
+/// left whole.
+pub fn trim_bounds(img: &DynamicImage, tolerance: u8) -> (u32, u32, u32, u32) {
+    let (width, height) = img.dimensions();
+    let uniform = |xs: Range<u32>, ys: Range<u32>| {
+        let color = img.get_pixel(xs.start, ys.start);
+        ys.flat_map(|y| xs.clone().map(move |x| (x, y)))
+            .all(|(x, y)| {
+                let pixel = img.get_pixel(x, y);
+                (pixel.0.iter().zip(color.0)).all(|(&a, b)| a.abs_diff(b) <= tolerance)
+            })
+    };
+
+    let (mut left, mut top, mut right, mut bottom) = (0, 0, width, height);
+    loop {
+        let before = (left, top, right, bottom);
+        while top < bottom && left < right && uniform(left..right, top..top + 1) {
+            top += 1;
+        }
+        while top < bottom && left < right && uniform(left..right, bottom - 1..bottom) {
+            bottom -= 1;
+        }
+        while top < bottom && left < right && uniform(left..left + 1, top..bottom) {
+            left += 1;
+        }
+        while top < bottom && left < right && uniform(right - 1..right, top..bottom) {
+            right -= 1;
+        }
+        if top == bottom || left == right {
+            return (0, 0, width, height);
+        }
+        if (left, top, right, bottom) == before {
+            return (left, top, right - left, bottom - top);
+        }
+    }
+}
+
 //https://github.com/libgd/libgd/blob/0d75136bd3e8651ded7c64a140791ed10de1c63c/src/gd.c#L3479-L3479
+//
+// The returned alpha channel holds the opacity of each output pixel, 255 being opaque.
 pub fn resized(img: &DynamicImage) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
-    const ALPHA_MAX: u8 = 127;
+    // libgd weighs each pixel by its opacity. Opaque pixels weigh 128 so that
+    // opaque images keep their existing hashes.
+    resized_weighted(img, |a| a as f32 * OPAQUE / 255.0)
+}
+
+/// Like [`resized`], but weighs translucent pixels the way iqdb-rs did before it
+/// handled alpha, by `127 - a` wrapped around to a byte, so that their hashes match
+/// the ones stored back then. Opaque pixels weigh 128 either way.
+pub fn resized_legacy(img: &DynamicImage) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    resized_weighted(img, |a| 127u8.wrapping_sub(a) as f32)
+}
+
+const OPAQUE: f32 = 128.0;
+
+fn resized_weighted(
+    img: &DynamicImage,
+    weight: impl Fn(u8) -> f32,
+) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
     let mut dst = image::ImageBuffer::<Rgba<u8>, _>::new(NUM_PIXELS as u32, NUM_PIXELS as u32);
     for y in 0..NUM_PIXELS {
         for x in 0..NUM_PIXELS {
             let mut spixels = 0.0;
-            let (mut red, mut green, mut blue, mut alpha) = (0.0, 0.0, 0.0, 0.0);
+            let (mut red, mut green, mut blue, mut alpha) = (0.0, 0.0, 0.0, 0.0f32);
             let (mut alpha_sum, mut contrib_sum) = (0.0, 0.0);
             let sy1 = y as f32 * img.height() as f32 / NUM_PIXELS as f32;
             let sy2 = (y + 1) as f32 * img.height() as f32 / NUM_PIXELS as f32;
@@ -290,11 +566,10 @@ pub fn resized(img: &DynamicImage) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
                     let pcontribution = xportion * yportion;
                     let Rgba([r, g, b, a]) = img.get_pixel(sx as u32, sy as u32);
 
-                    let alpha_factor = (ALPHA_MAX - a) as f32 * pcontribution;
+                    let alpha_factor = weight(a) * pcontribution;
                     red += r as f32 * alpha_factor;
                     green += g as f32 * alpha_factor;
                     blue += b as f32 * alpha_factor;
-                    alpha += a as f32 * alpha_factor;
                     alpha_sum += alpha_factor;
                     contrib_sum += pcontribution;
                     spixels += xportion * yportion;
@@ -307,12 +582,12 @@ pub fn resized(img: &DynamicImage) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
                 red /= spixels;
                 green /= spixels;
                 blue /= spixels;
-                alpha /= spixels;
             }
             if alpha_sum != 0.0 {
                 if contrib_sum != 0.0 {
                     alpha_sum /= contrib_sum;
                 }
+                alpha = alpha_sum / OPAQUE * 255.0;
                 red /= alpha_sum;
                 green /= alpha_sum;
                 blue /= alpha_sum;
@@ -321,7 +596,7 @@ pub fn resized(img: &DynamicImage) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
             red = red.round().min(255.0);
             green = green.round().min(255.0);
             blue = blue.round().min(255.0);
-            alpha = alpha.round().min(ALPHA_MAX as f32);
+            alpha = alpha.round().min(255.0);
 
             let pixel = dst.get_pixel_mut(x as u32, y as u32);
             pixel.0 = [red as u8, green as u8, blue as u8, alpha as u8];
@@ -329,3 +604,119 @@ pub fn resized(img: &DynamicImage) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
     }
     dst
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{pattern, signature};
+
+    #[test]
+    fn alpha() {
+        use image::{DynamicImage, Rgba, RgbaImage};
+
+        let image = |right: Rgba<u8>| {
+            let img = RgbaImage::from_fn(256, 256, |x, y| match (x < 128, y < 64) {
+                (true, _) => Rgba([200, 30, 40, 255]),
+                (false, true) => Rgba([10, 90, 220, 255]),
+                (false, false) => right,
+            });
+            DynamicImage::ImageRgba8(img)
+        };
+        let transparent = image(Rgba([0, 255, 0, 0]));
+        let with = |alpha| {
+            let options = SignatureOptions {
+                alpha,
+                ..Default::default()
+            };
+            Signature::from_image_with(&transparent, &options)
+        };
+
+        let white = Signature::from_image(&image(Rgba([255, 255, 255, 255])));
+        let black = Signature::from_image(&image(Rgba([0, 0, 0, 255])));
+        let orange = Signature::from_image(&image(Rgba([255, 136, 0, 255])));
+        assert_eq!(with(AlphaMode::WHITE), white);
+        assert_eq!(with(AlphaMode::BLACK), black);
+        assert_eq!(with("#ff8800".parse().unwrap()), orange);
+        assert_eq!(with(AlphaMode::Ignore), Signature::from_image(&transparent));
+        assert_eq!("white".parse(), Ok(AlphaMode::WHITE));
+        assert_eq!("ff880".parse::<AlphaMode>(), Err(()));
+    }
+
+    #[test]
+    fn invalid_hash() {
+        let hash = signature(1).to_string();
+        assert_eq!(hash.parse::<Signature>().unwrap(), signature(1));
+        // The first coefficient, outside the 128x128 transform.
+        let out_of_range = format!("{}4000{}", &hash[..53], &hash[57..]);
+        assert!(out_of_range.parse::<Signature>().is_err());
+        // The first coefficient, twice.
+        let repeated = format!("{}{}{}", &hash[..57], &hash[53..57], &hash[61..]);
+        assert!(matches!(
+            repeated.parse::<Signature>(),
+            Err(SignatureError::InvalidCoefficients)
+        ));
+        let not_hex = format!("{}zz{}", &hash[..60], &hash[62..]);
+        assert!(matches!(
+            not_hex.parse::<Signature>(),
+            Err(SignatureError::BadHex)
+        ));
+        let not_ascii = format!("{}é{}", &hash[..60], &hash[62..]);
+        assert!(not_ascii.parse::<Signature>().is_err());
+        assert!(matches!(
+            hash[..100].parse::<Signature>(),
+            Err(SignatureError::BadHashLength {
+                expected: 528,
+                found: 95
+            })
+        ));
+
+        let mut sig = signature(1);
+        sig.avgl.0 = f64::NAN;
+        assert!(!sig.is_valid());
+    }
+
+    #[test]
+    fn trim_borders() {
+        let content = pattern(120, 90, 5).to_rgb8();
+        // Letterboxed in black, with a slightly uneven white bar on the left.
+        let bordered = image::RgbImage::from_fn(140, 130, |x, y| match (x, y) {
+            (0..10, y) => image::Rgb([250 + (y % 3) as u8, 252, 255]),
+            (130.., _) | (_, ..20) | (_, 110..) => image::Rgb([2, 0, 1]),
+            (x, y) => *content.get_pixel(x - 10, y - 20),
+        });
+        let content = image::DynamicImage::ImageRgb8(content);
+        let bordered = image::DynamicImage::ImageRgb8(bordered);
+
+        let options = SignatureOptions {
+            trim: Some(8),
+            ..Default::default()
+        };
+        let (sig, bounds) = Signature::from_image_trimmed(&bordered, &options);
+        assert_eq!(bounds, (10, 20, 120, 90));
+        assert_eq!(sig, Signature::from_image(&content));
+        assert_ne!(Signature::from_image(&bordered), sig);
+        assert_eq!(trim_bounds(&bordered, 1), (0, 0, 130, 130));
+
+        let uniform = image::DynamicImage::new_rgb8(16, 16);
+        assert_eq!(trim_bounds(&uniform, 0), (0, 0, 16, 16));
+    }
+
+    #[test]
+    fn signature_from_bytes() {
+        let mut bytes = Vec::new();
+        let img = pattern(96, 64, 1);
+        img.write_to(&mut io::Cursor::new(&mut bytes), image::ImageFormat::Png)
+            .unwrap();
+        let options = SignatureOptions::default();
+        let sig = Signature::from_image(&img);
+        assert_eq!(Signature::from_bytes(&bytes, &options).unwrap(), sig);
+        assert_eq!(Signature::from_reader(&bytes[..], &options).unwrap(), sig);
+
+        let truncated = Signature::from_bytes(&bytes[..bytes.len() / 2], &options);
+        assert!(matches!(truncated, Err(SignatureError::Decode(_))));
+        let unknown = Signature::from_bytes(b"not an image", &options);
+        assert!(matches!(unknown, Err(SignatureError::UnsupportedFormat(_))));
+        let missing = Signature::from_path("does/not/exist.png", &options);
+        assert!(matches!(missing, Err(SignatureError::Io(_))));
+    }
+}
@@ -1,24 +1,38 @@
-#[cfg(target_feature = "avx512f")]
-use std::arch::x86_64::{_mm512_loadu_ps, _mm512_mask_sub_ps, _mm512_set1_ps, _mm512_storeu_ps};
+use std::io;
 
 use crate::{
-    bucket::{Bucket, Packed},
+    bucket::{Bucket, BucketRef, Packed},
     haar::Signature,
+    kernel::Kernel,
+    metadata::Metadata,
+    query::{QueryMode, QueryOptions, Weights},
+    snapshot::{invalid, SnapshotReader, SnapshotWriter},
 };
 
 pub(crate) type ChunkId = u16;
 pub(crate) const CHUNK_SIZE: u32 = ChunkId::MAX as u32 + 1;
+pub(crate) const BUCKET_COUNT: usize = 3 * 2 * 128 * 128;
+
+fn is_deleted(deleted: &[u64], slot: usize) -> bool {
+    deleted[slot / 64] & (1 << (slot % 64)) != 0
+}
 
 pub(crate) struct ImageIndex {
     offset: u32,
+    kernel: Kernel,
     avgl_y: Vec<f32>,
     avgl_i: Vec<f32>,
     avgl_q: Vec<f32>,
+    deleted: Vec<u64>,
+    // Metadata columns stay empty until an image in the chunk has any.
+    tags: Vec<u64>,
+    ratings: Vec<u8>,
+    timestamps: Vec<i64>,
     buckets: [[Vec<Bucket>; 2]; 3],
 }
 
 impl ImageIndex {
-    pub(crate) fn new(offset: u32) -> Self {
+    pub(crate) fn new(offset: u32, kernel: Kernel) -> Self {
         let buckets = {
             let vecs = vec![Bucket::new(); 128 * 128];
             let signs = [(); 2].map(|_| vecs.clone());
@@ -26,25 +40,46 @@ impl ImageIndex {
         };
         Self {
             offset,
+            kernel,
             avgl_y: Vec::with_capacity(CHUNK_SIZE as usize),
             avgl_i: Vec::with_capacity(CHUNK_SIZE as usize),
             avgl_q: Vec::with_capacity(CHUNK_SIZE as usize),
+            deleted: Vec::with_capacity(CHUNK_SIZE as usize / 64),
+            tags: Vec::new(),
+            ratings: Vec::new(),
+            timestamps: Vec::new(),
             buckets,
         }
     }
 
+    pub(crate) fn offset(&self) -> u32 {
+        self.offset
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.avgl_y.len()
+    }
+
     pub(crate) fn is_full(&self) -> bool {
         self.avgl_y.len() == CHUNK_SIZE as usize
     }
 
-    pub(crate) fn append(&mut self, index: u32, signature: Signature) {
+    pub(crate) fn append(&mut self, index: u32, signature: Signature, metadata: Metadata) {
         assert_eq!(self.offset + self.avgl_y.len() as u32, index, "Invalid ID");
+        if self.avgl_y.len().is_multiple_of(64) {
+            self.deleted.push(0);
+        }
+        if !self.tags.is_empty() || !metadata.is_empty() {
+            self.tags.resize(self.avgl_y.len(), 0);
+            self.ratings.resize(self.avgl_y.len(), 0);
+            self.timestamps.resize(self.avgl_y.len(), 0);
+            self.tags.push(metadata.tags);
+            self.ratings.push(metadata.rating);
+            self.timestamps.push(metadata.timestamp);
+        }
         self.avgl_y.push(signature.avgl.0 as f32);
         self.avgl_i.push(signature.avgl.1 as f32);
         self.avgl_q.push(signature.avgl.2 as f32);
-        if signature.avgl.0 == 0.0 {
-            return;
-        }
         let id = (index - self.offset) as ChunkId;
         for (coef_i, coef) in signature.sig.into_iter().enumerate() {
             let bucket = self.bucket_mut(coef_i / 40, coef);
@@ -53,39 +88,215 @@ impl ImageIndex {
     }
 
     pub(crate) fn remove(&mut self, index: u32, signature: Signature) {
-        let id = (index - self.offset) as usize;
-        if id < self.avgl_y.len() {
-            self.avgl_y[id] = 0.0;
+        if self.mark_deleted(index) {
+            let id = (index - self.offset) as ChunkId;
             for (coef_i, coef) in signature.sig.into_iter().enumerate() {
                 let bucket = self.bucket_mut(coef_i / 40, coef);
-                bucket.remove(id as ChunkId);
+                bucket.remove(id);
             }
         }
     }
 
-    fn bucket(&self, color: usize, coef: i16) -> &Bucket {
-        let sign = coef < 0;
-        &self.buckets[color][sign as usize][coef.unsigned_abs() as usize]
+    /// Hides a slot from queries without touching the buckets, returning
+    /// whether it was live. Its bucket entries are dropped by the next compaction.
+    pub(crate) fn mark_deleted(&mut self, index: u32) -> bool {
+        let id = (index - self.offset) as usize;
+        if id >= self.avgl_y.len() || is_deleted(&self.deleted, id) {
+            return false;
+        }
+        self.deleted[id / 64] |= 1 << (id % 64);
+        true
+    }
+
+    pub(crate) fn is_deleted(&self, index: u32) -> bool {
+        is_deleted(&self.deleted, (index - self.offset) as usize)
+    }
+
+    pub(crate) fn has_deleted(&self) -> bool {
+        self.deleted.iter().any(|&bits| bits != 0)
+    }
+
+    /// Rebuilds the signature of every slot from the buckets.
+    ///
+    /// Coefficients come out sorted per color, matching [`crate::SqlDB`].
+    pub(crate) fn signatures(&self) -> Vec<Signature> {
+        self.as_ref().signatures()
+    }
+
+    pub(crate) fn save(&self, writer: &mut SnapshotWriter) -> io::Result<()> {
+        let mut directory = Vec::with_capacity(BUCKET_COUNT * 2);
+        let mut pool = Vec::new();
+        for bucket in self.buckets.iter().flatten().flatten() {
+            directory.extend(bucket.encode(&mut pool));
+        }
+        writer.u32(self.offset)?;
+        writer.u32(self.avgl_y.len() as u32)?;
+        writer.u64(pool.len() as u64)?;
+        writer.u64(self.tags.len() as u64)?;
+        writer.slice(&self.avgl_y)?;
+        writer.slice(&self.avgl_i)?;
+        writer.slice(&self.avgl_q)?;
+        writer.slice(&self.deleted)?;
+        writer.slice(&self.tags)?;
+        writer.slice(&self.timestamps)?;
+        writer.slice(&self.ratings)?;
+        writer.slice(&directory)?;
+        writer.slice(&pool)
+    }
+
+    pub(crate) fn load(reader: &mut SnapshotReader, kernel: Kernel) -> io::Result<Self> {
+        let offset = reader.u32()?;
+        let total = reader.u32()?;
+        let pool_len = reader.u64()?;
+        let metadata_len = reader.u64()?;
+        if offset % CHUNK_SIZE != 0 || total > CHUNK_SIZE {
+            return Err(invalid("invalid chunk header"));
+        }
+        if metadata_len != 0 && metadata_len != total as u64 {
+            return Err(invalid("invalid chunk header"));
+        }
+        let mut index = Self::new(offset, kernel);
+        index.avgl_y = reader.vec(total as u64)?;
+        index.avgl_i = reader.vec(total as u64)?;
+        index.avgl_q = reader.vec(total as u64)?;
+        index.deleted = reader.vec(total.div_ceil(64) as u64)?;
+        index.tags = reader.vec(metadata_len)?;
+        index.timestamps = reader.vec(metadata_len)?;
+        index.ratings = reader.vec(metadata_len)?;
+        let directory: Vec<[u32; 2]> = reader.vec(BUCKET_COUNT as u64)?;
+        let pool: Vec<Packed> = reader.vec(pool_len)?;
+        let buckets = index.buckets.iter_mut().flatten().flatten();
+        for (bucket, &entry) in buckets.zip(&directory) {
+            *bucket = Bucket::decode(entry, &pool, total as usize)
+                .ok_or_else(|| invalid("invalid bucket"))?;
+        }
+        Ok(index)
+    }
+
+    pub(crate) fn as_ref(&self) -> ChunkRef<'_> {
+        ChunkRef {
+            offset: self.offset,
+            kernel: self.kernel,
+            avgl_y: &self.avgl_y,
+            avgl_i: &self.avgl_i,
+            avgl_q: &self.avgl_q,
+            deleted: &self.deleted,
+            tags: &self.tags,
+            ratings: &self.ratings,
+            timestamps: &self.timestamps,
+            buckets: Buckets::Memory(&self.buckets),
+        }
     }
 
     fn bucket_mut(&mut self, color: usize, coef: i16) -> &mut Bucket {
         let sign = coef < 0;
         &mut self.buckets[color][sign as usize][coef.unsigned_abs() as usize]
     }
+}
+
+/// A read-only view of one chunk, borrowed from an [`ImageIndex`] or a mapped snapshot.
+#[derive(Clone, Copy)]
+pub(crate) struct ChunkRef<'a> {
+    pub(crate) offset: u32,
+    pub(crate) kernel: Kernel,
+    pub(crate) avgl_y: &'a [f32],
+    pub(crate) avgl_i: &'a [f32],
+    pub(crate) avgl_q: &'a [f32],
+    pub(crate) deleted: &'a [u64],
+    /// Either empty or one entry per slot, like `ratings` and `timestamps`.
+    pub(crate) tags: &'a [u64],
+    pub(crate) ratings: &'a [u8],
+    pub(crate) timestamps: &'a [i64],
+    pub(crate) buckets: Buckets<'a>,
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum Buckets<'a> {
+    Memory(&'a [[Vec<Bucket>; 2]; 3]),
+    Mapped {
+        directory: &'a [[u32; 2]],
+        pool: &'a [Packed],
+        /// Unverified buckets may hold ids past the end of the chunk.
+        verified: bool,
+    },
+}
+
+impl<'a> ChunkRef<'a> {
+    fn bucket(&self, color: usize, coef: i16) -> BucketRef<'a> {
+        let sign = coef < 0;
+        match self.buckets {
+            Buckets::Memory(buckets) => {
+                buckets[color][sign as usize][coef.unsigned_abs() as usize].as_ref()
+            }
+            Buckets::Mapped {
+                directory, pool, ..
+            } => {
+                let index = (color * 2 + sign as usize) * 128 * 128;
+                let entry = directory[index + coef.unsigned_abs() as usize];
+                BucketRef::from_entry(entry, pool)
+            }
+        }
+    }
+
+    pub(crate) fn is_deleted(&self, index: u32) -> bool {
+        is_deleted(self.deleted, (index - self.offset) as usize)
+    }
+
+    pub(crate) fn metadata(&self, slot: usize) -> Metadata {
+        if self.tags.is_empty() {
+            return Metadata::default();
+        }
+        Metadata {
+            tags: self.tags[slot],
+            rating: self.ratings[slot],
+            timestamp: self.timestamps[slot],
+        }
+    }
 
-    pub(crate) fn query(&self, looking_for: &Signature, limit: usize) -> Vec<(f32, u32)> {
-        const WEIGHTS: [[f32; 3]; 6] = [
-            [5.00, 19.21, 34.37],
-            [0.83, 1.26, 0.36],
-            [1.01, 0.44, 0.45],
-            [0.52, 0.53, 0.14],
-            [0.47, 0.28, 0.18],
-            [0.30, 0.14, 0.27],
-        ];
+    /// Rebuilds the signature of every slot from the buckets, sorted per color.
+    pub(crate) fn signatures(&self) -> Vec<Signature> {
+        let mut signatures: Vec<_> = (0..self.avgl_y.len())
+            .map(|i| Signature {
+                avgl: (
+                    self.avgl_y[i] as f64,
+                    self.avgl_i[i] as f64,
+                    self.avgl_q[i] as f64,
+                ),
+                sig: Vec::with_capacity(120),
+            })
+            .collect();
+        const COEFS: i16 = 128 * 128 - 1;
+        for color in 0..3 {
+            for coef in (-COEFS..0).chain(1..=COEFS) {
+                for id in self.bucket(color, coef).ids() {
+                    signatures[id as usize].sig.push(coef);
+                }
+            }
+        }
+        signatures
+    }
+
+    /// Scores every slot against `looking_for`, returning the raw scores and
+    /// the factor that, times 100, scales them to 0..=100.
+    fn scores(
+        &self,
+        looking_for: &Signature,
+        weights: &Weights,
+        mode: QueryMode,
+    ) -> (Vec<f32>, f32) {
+        let kernel = self.kernel;
+        let chroma = mode == QueryMode::Color;
         let total = self.avgl_y.len();
 
+        // Leave room for any id an unverified snapshot may hold.
+        let len = match self.buckets {
+            Buckets::Mapped {
+                verified: false, ..
+            } => CHUNK_SIZE as usize,
+            _ => total,
+        };
         let mut scale = 0.;
-        let mut scores: Vec<f32> = vec![0.; total + Packed::BITS as usize];
+        let mut scores: Vec<f32> = vec![0.; len + Packed::BITS as usize];
 
         assert!(total <= self.avgl_y.len());
         assert!(total <= self.avgl_i.len());
@@ -94,64 +305,90 @@ impl ImageIndex {
         #[allow(clippy::needless_range_loop)]
         for i in 0..total {
             let mut score = 0.;
-            score += WEIGHTS[0][0] * (self.avgl_y[i] - looking_for.avgl.0 as f32).abs();
-            score += WEIGHTS[0][1] * (self.avgl_i[i] - looking_for.avgl.1 as f32).abs();
-            score += WEIGHTS[0][2] * (self.avgl_q[i] - looking_for.avgl.2 as f32).abs();
+            score += weights[0][0] * (self.avgl_y[i] - looking_for.avgl.0 as f32).abs();
+            if chroma {
+                score += weights[0][1] * (self.avgl_i[i] - looking_for.avgl.1 as f32).abs();
+                score += weights[0][2] * (self.avgl_q[i] - looking_for.avgl.2 as f32).abs();
+            }
             scores[i] = score;
         }
 
-        for (coef_i, &coef) in looking_for.sig.iter().enumerate() {
+        let colors = if chroma { 3 } else { 1 };
+        for (coef_i, &coef) in looking_for.sig.iter().enumerate().take(colors * 40) {
             let bucket = self.bucket(coef_i / 40, coef);
 
             let w = coef.unsigned_abs();
             let w = (w / 128).max(w % 128).min(5) as usize;
-            let weight = WEIGHTS[w][coef_i / 40];
+            let weight = weights[w][coef_i / 40];
             scale -= weight;
 
-            match &bucket {
-                Bucket::Empty => {}
-                Bucket::Array(ids) => {
-                    let index = ids[0] as usize;
-                    *unsafe { scores.get_unchecked_mut(index) } -= weight;
-                    for &id in &ids[1..] {
-                        if id == 0 {
-                            break;
-                        }
-                        let index = id as usize;
-                        *unsafe { scores.get_unchecked_mut(index) } -= weight;
-                    }
-                }
-                Bucket::Vec(ids) => {
+            match bucket {
+                BucketRef::Ids(ids) => {
                     for &id in ids {
                         let index = id as usize;
+                        // ids are always below `scores.len()`, checked on load for
+                        // verified snapshots
                         *unsafe { scores.get_unchecked_mut(index) } -= weight;
                     }
                 }
-                Bucket::Mask(mask) => {
-                    #[cfg(target_feature = "avx512f")]
-                    {
-                        let m_weight = unsafe { _mm512_set1_ps(weight) };
-                        for (index, &m) in mask.iter().enumerate() {
-                            let index = index * 16;
-                            let m_score = unsafe { _mm512_loadu_ps(scores.as_ptr().add(index)) };
-                            let m_score =
-                                unsafe { _mm512_mask_sub_ps(m_score, m, m_score, m_weight) };
-                            unsafe { _mm512_storeu_ps(scores.as_mut_ptr().add(index), m_score) };
-                        }
-                    }
-                    #[cfg(not(target_feature = "avx512f"))]
-                    {
-                        let _ = mask;
-                        unreachable!()
-                    }
-                }
+                BucketRef::Mask(mask) => kernel.mask_sub(&mut scores, mask, weight),
             }
         }
 
+        if scale != 0. {
+            scale = 1. / scale;
+        }
+        (scores, scale)
+    }
+
+    /// Every slot, deleted ones aside, that matches the filter of `options` and
+    /// scores at least its `min_score` against `looking_for`, unsorted.
+    pub(crate) fn above(&self, looking_for: &Signature, options: &QueryOptions) -> Vec<(f32, u32)> {
+        let (scores, scale) = self.scores(looking_for, &options.weights, options.mode);
+        let filter = &options.filter;
+        let min_score = options.min_score.unwrap_or(f32::MIN);
+        let total = self.avgl_y.len();
+
+        let filtered = !filter.is_empty();
+        scores
+            .into_iter()
+            .take(total)
+            .enumerate()
+            .filter(|&(index, _)| !is_deleted(self.deleted, index))
+            .filter(|&(index, _)| !filtered || filter.matches(&self.metadata(index)))
+            .map(|(index, score)| (score * 100. * scale, index as u32 + self.offset))
+            .filter(|&(score, _)| score >= min_score)
+            .collect()
+    }
+
+    pub(crate) fn query(
+        &self,
+        looking_for: &Signature,
+        limit: usize,
+        options: &QueryOptions,
+    ) -> Vec<(f32, u32)> {
+        let total = self.avgl_y.len();
+        // No more results than slots, however large the limit asked for.
+        let limit = limit.min(total);
+        if limit == 0 {
+            return Vec::new();
+        }
+        let (scores, scale) = self.scores(looking_for, &options.weights, options.mode);
+        let filter = &options.filter;
+
+        let filtered = !filter.is_empty();
         let mut sorted = vec![(f32::MAX, 0); limit + 1];
         for (index, score) in scores.into_iter().enumerate().take(total) {
-            // is_deleted
-            if self.avgl_y[index] == 0. {
+            if is_deleted(self.deleted, index) {
+                continue;
+            }
+            if options
+                .min_score
+                .is_some_and(|min_score| score * 100. * scale < min_score)
+            {
+                continue;
+            }
+            if filtered && !filter.matches(&self.metadata(index)) {
                 continue;
             }
             if score >= sorted[limit - 1].0 {
@@ -172,12 +409,113 @@ impl ImageIndex {
         }
         sorted.retain(|&(score, id)| !(id == 0 && score == f32::MAX));
 
-        if scale != 0. {
-            scale = 1. / scale;
-        }
         sorted
             .into_iter()
             .map(|(score, index)| (score * 100. * scale, index + self.offset))
             .collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        testing::{ids, image, signature},
+        QueryMode, QueryOptions, Signature, WeightProfile, DB,
+    };
+
+    /// Image `k` shares `40 - k` coefficients with `signature(1)`, so they rank in order.
+    fn fading() -> DB {
+        DB::new((0..40).map(|k| image(k, 1 + k as i16)))
+    }
+
+    #[test]
+    fn black_image() {
+        let mut black = image(1, 1);
+        black.avgl = (0., 0., 0.);
+        let mut db = DB::new([black.clone(), image(2, 1)]);
+        let sig = Signature {
+            avgl: black.avgl,
+            sig: black.sig.clone(),
+        };
+        assert_eq!(ids(db.query(&sig, 2)), [1, 2]);
+
+        db.delete(black).unwrap();
+        assert_eq!(db.deleted_count(), 1);
+        assert_eq!(ids(db.query(&sig, 2)), [2]);
+    }
+
+    #[test]
+    fn min_score() {
+        let db = fading();
+        let sig = signature(1);
+        let all = db.query(&sig, 40);
+        assert_eq!(ids(all.clone()), (0..40).collect::<Vec<_>>());
+
+        let options = QueryOptions {
+            min_score: Some(all[10].0),
+            ..Default::default()
+        };
+        assert_eq!(db.query_with(&sig, 40, &options), all[..=10]);
+        assert_eq!(db.query_with(&sig, 5, &options), all[..5]);
+
+        let options = QueryOptions {
+            min_score: Some(101.),
+            ..Default::default()
+        };
+        assert_eq!(db.query_with(&sig, 40, &options), []);
+    }
+
+    #[test]
+    fn weights() {
+        let db = fading();
+        let sig = signature(1);
+        let photo = db.query(&sig, 40);
+
+        let sketch = QueryOptions {
+            weights: WeightProfile::Sketch.weights(),
+            ..Default::default()
+        };
+        let result = db.query_with(&sig, 40, &sketch);
+        assert!((result[0].0 - 100.).abs() < 0.01);
+        assert_ne!(result, photo);
+        assert_eq!(ids(result), ids(photo.clone()));
+
+        // Only the ratios between weights matter.
+        let mut doubled = QueryOptions::default();
+        doubled.weights.iter_mut().flatten().for_each(|w| *w *= 2.);
+        let result = db.query_with(&sig, 40, &doubled);
+        assert!(result
+            .iter()
+            .zip(&photo)
+            .all(|(a, b)| (a.0 - b.0).abs() < 0.01));
+
+        assert_eq!(
+            WeightProfile::of(&sketch.weights),
+            Some(WeightProfile::Sketch)
+        );
+        assert_eq!(WeightProfile::of(&doubled.weights), None);
+        assert_eq!("sketch".parse(), Ok(WeightProfile::Sketch));
+        assert_eq!(WeightProfile::Sketch.name(), "sketch");
+    }
+
+    #[test]
+    fn grayscale() {
+        // The luminance of image 1, with other chroma.
+        let mut gray = image(2, 1);
+        gray.avgl = (0.5, 0.3, -0.3);
+        gray.sig[40..].iter_mut().for_each(|coef| *coef += 3000);
+        let db = DB::new([image(1, 1), gray, image(3, 21)]);
+        let sig = signature(1);
+        assert_eq!(ids(db.query(&sig, 3)), [1, 3, 2]);
+
+        let options = QueryOptions {
+            mode: QueryMode::Grayscale,
+            ..Default::default()
+        };
+        let result = db.query_with(&sig, 3, &options);
+        assert!(result[..2].iter().all(|r| (r.0 - 100.).abs() < 0.01));
+        assert_eq!(result[2].1, 3);
+        assert_eq!("grayscale".parse(), Ok(QueryMode::Grayscale));
+        assert_eq!(QueryMode::Grayscale.name(), "grayscale");
+    }
+}
@@ -0,0 +1,220 @@
+use crate::bucket::Packed;
+
+const LANES: usize = Packed::BITS as usize;
+
+/// The SIMD strategy used to score `Bucket::Mask` entries.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kernel {
+    Avx512,
+    Avx2,
+    Sse41,
+    Neon,
+    Scalar,
+}
+
+impl Kernel {
+    const ALL: [Kernel; 5] = [
+        Self::Avx512,
+        Self::Avx2,
+        Self::Sse41,
+        Self::Neon,
+        Self::Scalar,
+    ];
+
+    /// Picks the fastest kernel supported by the running CPU.
+    pub fn detect() -> Self {
+        Self::available().next().unwrap_or(Self::Scalar)
+    }
+
+    /// All kernels supported by the running CPU, fastest first.
+    pub fn available() -> impl Iterator<Item = Kernel> {
+        Self::ALL.into_iter().filter(|kernel| kernel.is_supported())
+    }
+
+    pub fn is_supported(self) -> bool {
+        match self {
+            #[cfg(target_arch = "x86_64")]
+            Self::Avx512 => std::arch::is_x86_feature_detected!("avx512bw"),
+            #[cfg(target_arch = "x86_64")]
+            Self::Avx2 => std::arch::is_x86_feature_detected!("avx2"),
+            #[cfg(target_arch = "x86_64")]
+            Self::Sse41 => std::arch::is_x86_feature_detected!("sse4.1"),
+            #[cfg(target_arch = "aarch64")]
+            Self::Neon => std::arch::is_aarch64_feature_detected!("neon"),
+            Self::Scalar => true,
+            #[allow(unreachable_patterns)]
+            _ => false,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Avx512 => "avx512",
+            Self::Avx2 => "avx2",
+            Self::Sse41 => "sse4.1",
+            Self::Neon => "neon",
+            Self::Scalar => "scalar",
+        }
+    }
+
+    /// Subtracts `weight` from every score whose bit is set in `mask`.
+    ///
+    /// Bit `b` of `mask[i]` maps to `scores[i * 16 + b]`.
+    pub(crate) fn mask_sub(self, scores: &mut [f32], mask: &[Packed], weight: f32) {
+        assert!(mask.len() * LANES <= scores.len());
+        match self {
+            #[cfg(target_arch = "x86_64")]
+            Self::Avx512 => unsafe { x86::mask_sub_avx512(scores, mask, weight) },
+            #[cfg(target_arch = "x86_64")]
+            Self::Avx2 => unsafe { x86::mask_sub_avx2(scores, mask, weight) },
+            #[cfg(target_arch = "x86_64")]
+            Self::Sse41 => unsafe { x86::mask_sub_sse41(scores, mask, weight) },
+            #[cfg(target_arch = "aarch64")]
+            Self::Neon => unsafe { aarch64::mask_sub_neon(scores, mask, weight) },
+            #[allow(unreachable_patterns)]
+            _ => mask_sub_scalar(scores, mask, weight),
+        }
+    }
+}
+
+fn mask_sub_scalar(scores: &mut [f32], mask: &[Packed], weight: f32) {
+    for (index, &m) in mask.iter().enumerate() {
+        let index = index * LANES;
+        let mut m = m;
+        while m != 0 {
+            let offset = m.trailing_zeros() as usize;
+            scores[index + offset] -= weight;
+            m &= m - 1;
+        }
+    }
+}
+
+// Every kernel below must produce bit-identical results to `mask_sub_scalar`.
+// Unset lanes are either left untouched or have `0.0` subtracted, which is exact.
+
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use std::arch::x86_64::*;
+
+    use super::{Packed, LANES};
+
+    #[target_feature(enable = "avx512f,avx512bw")]
+    pub(super) unsafe fn mask_sub_avx512(scores: &mut [f32], mask: &[Packed], weight: f32) {
+        let m_weight = _mm512_set1_ps(weight);
+        for (index, &m) in mask.iter().enumerate() {
+            let ptr = scores.as_mut_ptr().add(index * LANES);
+            let m_score = _mm512_loadu_ps(ptr);
+            let m_score = _mm512_mask_sub_ps(m_score, m, m_score, m_weight);
+            _mm512_storeu_ps(ptr, m_score);
+        }
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub(super) unsafe fn mask_sub_avx2(scores: &mut [f32], mask: &[Packed], weight: f32) {
+        let m_weight = _mm256_set1_ps(weight);
+        let bits = [0, 8].map(|shift| {
+            _mm256_setr_epi32(
+                1 << shift,
+                2 << shift,
+                4 << shift,
+                8 << shift,
+                16 << shift,
+                32 << shift,
+                64 << shift,
+                128 << shift,
+            )
+        });
+        for (index, &m) in mask.iter().enumerate() {
+            if m == 0 {
+                continue;
+            }
+            let m = _mm256_set1_epi32(m as i32);
+            for (half, &bits) in bits.iter().enumerate() {
+                let ptr = scores.as_mut_ptr().add(index * LANES + half * 8);
+                let m_lanes = _mm256_cmpeq_epi32(_mm256_and_si256(m, bits), bits);
+                let m_sub = _mm256_and_ps(_mm256_castsi256_ps(m_lanes), m_weight);
+                let m_score = _mm256_loadu_ps(ptr);
+                _mm256_storeu_ps(ptr, _mm256_sub_ps(m_score, m_sub));
+            }
+        }
+    }
+
+    #[target_feature(enable = "sse4.1")]
+    pub(super) unsafe fn mask_sub_sse41(scores: &mut [f32], mask: &[Packed], weight: f32) {
+        let m_weight = _mm_set1_ps(weight);
+        let bits = [0, 4, 8, 12]
+            .map(|shift| _mm_setr_epi32(1 << shift, 2 << shift, 4 << shift, 8 << shift));
+        for (index, &m) in mask.iter().enumerate() {
+            if m == 0 {
+                continue;
+            }
+            let m = _mm_set1_epi32(m as i32);
+            for (quarter, &bits) in bits.iter().enumerate() {
+                let ptr = scores.as_mut_ptr().add(index * LANES + quarter * 4);
+                let m_lanes = _mm_cmpeq_epi32(_mm_and_si128(m, bits), bits);
+                let m_score = _mm_loadu_ps(ptr);
+                let m_sub = _mm_sub_ps(m_score, m_weight);
+                _mm_storeu_ps(
+                    ptr,
+                    _mm_blendv_ps(m_score, m_sub, _mm_castsi128_ps(m_lanes)),
+                );
+            }
+        }
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod aarch64 {
+    use std::arch::aarch64::*;
+
+    use super::{Packed, LANES};
+
+    #[target_feature(enable = "neon")]
+    pub(super) unsafe fn mask_sub_neon(scores: &mut [f32], mask: &[Packed], weight: f32) {
+        let m_weight = vdupq_n_f32(weight);
+        let bits = [0, 4, 8, 12].map(|shift| {
+            let bits: [u32; 4] = [1 << shift, 2 << shift, 4 << shift, 8 << shift];
+            vld1q_u32(bits.as_ptr())
+        });
+        for (index, &m) in mask.iter().enumerate() {
+            if m == 0 {
+                continue;
+            }
+            let m = vdupq_n_u32(m as u32);
+            for (quarter, &bits) in bits.iter().enumerate() {
+                let ptr = scores.as_mut_ptr().add(index * LANES + quarter * 4);
+                let m_lanes = vtstq_u32(m, bits);
+                let m_score = vld1q_f32(ptr);
+                let m_sub = vsubq_f32(m_score, m_weight);
+                vst1q_f32(ptr, vbslq_f32(m_lanes, m_sub, m_score));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mask_kernels() {
+        let mask: Vec<Packed> = (0..512)
+            .map(|i| (i * 0x9e37) as u16 & (i * 0x3c6e) as u16)
+            .collect();
+        let initial: Vec<f32> = (0..mask.len() * LANES + LANES)
+            .map(|i| (i * 7_919 % 10_000) as f32 / 100. - 50.)
+            .collect();
+
+        let mut expected = initial.clone();
+        for weight in [0.83, 19.21, 0.14] {
+            Kernel::Scalar.mask_sub(&mut expected, &mask, weight);
+        }
+        for kernel in Kernel::available() {
+            let mut scores = initial.clone();
+            for weight in [0.83, 19.21, 0.14] {
+                kernel.mask_sub(&mut scores, &mask, weight);
+            }
+            assert_eq!(scores, expected, "{}", kernel.name());
+        }
+    }
+}
@@ -1,92 +1,620 @@
-#![feature(stdarch_x86_avx512)]
-
-use std::collections::HashMap;
+use std::{
+    collections::{btree_map, hash_map::Entry, BTreeMap, HashMap, HashSet},
+    fmt::Display,
+    io,
+    path::Path,
+};
 
+pub use duplicates::{clusters, Duplicate, DuplicateSearch};
 #[cfg(feature = "multi-thread")]
 use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
 
-pub use haar::Signature;
-use index::ImageIndex;
+pub use frames::{decode_frames, decode_frames_scaled, FrameSelection, MIN_DECODE_SIZE};
+pub use haar::{trim_bounds, AlphaMode, Signature, SignatureError, SignatureOptions};
+use index::{ChunkRef, ImageIndex};
+pub use kernel::Kernel;
+use mapped::MappedDB;
+pub use metadata::{Filter, Metadata};
+pub use query::{QueryMode, QueryOptions, WeightProfile, Weights};
+pub use regions::Region;
+pub use resample::{BoxFilter, Libgd, LibgdLegacy, Resampler, Resampling};
+use snapshot::{invalid, Header, SnapshotReader, SnapshotWriter};
 pub use sql::{ImageData, SqlDB, SqlSchema};
+pub use transform::Transform;
 
 use crate::index::CHUNK_SIZE;
 
 mod bucket;
+mod duplicates;
+mod frames;
 mod haar;
 mod index;
+mod kernel;
+mod mapped;
+mod metadata;
+mod query;
+mod regions;
+mod resample;
+mod snapshot;
 mod sql;
+#[cfg(test)]
+mod testing;
+mod transform;
+
+/// Returned when mutating a [`DB`] opened with [`DB::open_snapshot`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReadOnlyError;
+
+impl Display for ReadOnlyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "database is read-only")
+    }
+}
+
+impl std::error::Error for ReadOnlyError {}
 
 pub struct DB {
+    kernel: Kernel,
     indexes: Vec<ImageIndex>,
     index_to_id: Vec<i64>,
     id_to_index: HashMap<i64, u32>,
+    /// The slots of every frame but the first, for animated images.
+    frames: HashMap<i64, Vec<u32>>,
+    /// How many images have each number of extra frames, so that the most frames of
+    /// any image is known without a scan.
+    frame_counts: BTreeMap<usize, usize>,
+    mapped: Option<MappedDB>,
 }
 
 impl DB {
+    /// Builds an index of `images`. Images sharing an id are added as frames of one
+    /// image, the first one first, as [`SqlDB::load`] returns them.
     pub fn new(images: impl IntoIterator<Item = ImageData>) -> Self {
         let mut db = Self {
+            kernel: Kernel::detect(),
             indexes: Vec::new(),
             index_to_id: Vec::new(),
             id_to_index: HashMap::new(),
+            frames: HashMap::new(),
+            frame_counts: BTreeMap::new(),
+            mapped: None,
         };
         for image in images.into_iter() {
-            db.insert(image);
+            db.append(image);
         }
         println!("TotalImages: {}", db.index_to_id.len());
+        println!("Kernel: {}", db.kernel.name());
+        db
+    }
+
+    /// Writes the whole index to `path`, replacing it atomically.
+    pub fn save_snapshot(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        if self.mapped.is_some() {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, ReadOnlyError));
+        }
+        let path = path.as_ref();
+        let temp_path = path.with_extension("tmp");
+        let header = Header {
+            chunks: self.indexes.len() as u32,
+            slots: self.index_to_id.len() as u64,
+            live: self.id_to_index.len() as u64,
+            checksum: 0,
+        };
+        let mut writer = SnapshotWriter::create(&temp_path, header)?;
+        writer.slice(&self.index_to_id)?;
+
+        let mut live: Vec<_> = self.id_to_index.iter().map(|(&id, &i)| (id, i)).collect();
+        live.sort_unstable();
+        let ids: Vec<i64> = live.iter().map(|&(id, _)| id).collect();
+        let indexes: Vec<u32> = live.iter().map(|&(_, index)| index).collect();
+        writer.slice(&ids)?;
+        writer.slice(&indexes)?;
+
+        let mut frames: Vec<_> = self
+            .frames
+            .iter()
+            .flat_map(|(&id, slots)| slots.iter().map(move |&index| (id, index)))
+            .collect();
+        frames.sort_unstable();
+        let ids: Vec<i64> = frames.iter().map(|&(id, _)| id).collect();
+        let indexes: Vec<u32> = frames.iter().map(|&(_, index)| index).collect();
+        writer.u64(frames.len() as u64)?;
+        writer.slice(&ids)?;
+        writer.slice(&indexes)?;
+
+        for image_index in &self.indexes {
+            image_index.save(&mut writer)?;
+        }
+        writer.finish()?;
+        std::fs::rename(temp_path, path)
+    }
+
+    /// Loads an index written by [`DB::save_snapshot`].
+    pub fn load_snapshot(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut reader = SnapshotReader::open(path.as_ref())?;
+        let header = reader.header();
+        if header.slots > header.chunks as u64 * CHUNK_SIZE as u64 || header.live > header.slots {
+            return Err(invalid("invalid snapshot header"));
+        }
+
+        let index_to_id: Vec<i64> = reader.vec(header.slots)?;
+        let ids: Vec<i64> = reader.vec(header.live)?;
+        let indexes: Vec<u32> = reader.vec(header.live)?;
+        if indexes.iter().any(|&index| index as u64 >= header.slots) {
+            return Err(invalid("invalid image slot"));
+        }
+        let id_to_index: HashMap<_, _> = ids.into_iter().zip(indexes).collect();
+
+        let frame_count = reader.u64()?;
+        if frame_count > header.slots {
+            return Err(invalid("invalid frame count"));
+        }
+        let ids: Vec<i64> = reader.vec(frame_count)?;
+        let indexes: Vec<u32> = reader.vec(frame_count)?;
+        if indexes.iter().any(|&index| index as u64 >= header.slots) {
+            return Err(invalid("invalid image slot"));
+        }
+        let mut frames: HashMap<_, Vec<_>> = HashMap::new();
+        for (id, index) in ids.into_iter().zip(indexes) {
+            frames.entry(id).or_default().push(index);
+        }
+        let mut frame_counts = BTreeMap::new();
+        for slots in frames.values() {
+            *frame_counts.entry(slots.len()).or_default() += 1;
+        }
+
+        let kernel = Kernel::detect();
+        let mut indexes = Vec::with_capacity(header.chunks as usize);
+        let mut total = 0;
+        for chunk in 0..header.chunks {
+            let image_index = ImageIndex::load(&mut reader, kernel)?;
+            if image_index.offset() != chunk * CHUNK_SIZE {
+                return Err(invalid("invalid chunk offset"));
+            }
+            if chunk + 1 < header.chunks && !image_index.is_full() {
+                return Err(invalid("invalid chunk length"));
+            }
+            total += image_index.len() as u64;
+            indexes.push(image_index);
+        }
+        if total != header.slots {
+            return Err(invalid("invalid chunk length"));
+        }
+        reader.finish()?;
+
+        let db = Self {
+            kernel,
+            indexes,
+            index_to_id,
+            id_to_index,
+            frames,
+            frame_counts,
+            mapped: None,
+        };
+        println!("TotalImages: {}", db.index_to_id.len());
+        println!("Kernel: {}", db.kernel.name());
+        Ok(db)
+    }
+
+    /// Serves queries straight from a memory-mapped snapshot.
+    ///
+    /// The snapshot is shared with every other process mapping the same file,
+    /// and all mutations return [`ReadOnlyError`]. Only the header and section
+    /// bounds are checked, so opening takes about the same time for any size.
+    pub fn open_snapshot(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::mapped(MappedDB::open(path.as_ref())?))
+    }
+
+    /// Like [`DB::open_snapshot`], but also verifies the checksum and every bucket,
+    /// which reads the whole file.
+    pub fn open_snapshot_verified(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::mapped(MappedDB::open_verified(path.as_ref())?))
+    }
+
+    fn mapped(mapped: MappedDB) -> Self {
+        let db = Self {
+            kernel: Kernel::detect(),
+            indexes: Vec::new(),
+            index_to_id: Vec::new(),
+            id_to_index: HashMap::new(),
+            frames: HashMap::new(),
+            frame_counts: BTreeMap::new(),
+            mapped: Some(mapped),
+        };
+        println!("TotalImages: {}", db.image_count());
+        println!("Kernel: {}", db.kernel.name());
         db
     }
 
+    pub fn is_read_only(&self) -> bool {
+        self.mapped.is_some()
+    }
+
     pub fn contains(&self, id: i64) -> bool {
-        self.id_to_index.contains_key(&id)
+        match &self.mapped {
+            Some(mapped) => mapped.contains(id),
+            None => self.id_to_index.contains_key(&id),
+        }
     }
 
     pub fn image_count(&self) -> usize {
-        self.id_to_index.len()
+        match &self.mapped {
+            Some(mapped) => mapped.image_count(),
+            None => self.id_to_index.len(),
+        }
+    }
+
+    /// The scoring kernel selected for this CPU.
+    pub fn kernel(&self) -> Kernel {
+        self.kernel
+    }
+
+    /// The number of slots in the index, including those of extra frames and deleted images.
+    pub fn slot_count(&self) -> usize {
+        match &self.mapped {
+            Some(mapped) => mapped.index_to_id().len(),
+            None => self.index_to_id.len(),
+        }
+    }
+
+    /// The number of extra frames stored for animated images.
+    pub fn frame_count(&self) -> usize {
+        match &self.mapped {
+            Some(mapped) => mapped.frame_count(),
+            None => self.frame_counts.iter().map(|(frames, n)| frames * n).sum(),
+        }
+    }
+
+    /// The most frames stored for a single image.
+    fn max_frames(&self) -> usize {
+        match &self.mapped {
+            Some(mapped) => mapped.max_frames(),
+            None => self.frame_counts.keys().next_back().copied().unwrap_or(0) + 1,
+        }
+    }
+
+    /// Slots still held by deleted images.
+    pub fn deleted_count(&self) -> usize {
+        self.slot_count() - self.image_count() - self.frame_count()
+    }
+
+    /// The fraction of slots held by deleted images.
+    pub fn deleted_ratio(&self) -> f64 {
+        let slots = self.slot_count();
+        if slots == 0 {
+            return 0.;
+        }
+        self.deleted_count() as f64 / slots as f64
+    }
+
+    /// Drops the slots of deleted images, renumbering the remaining ones.
+    ///
+    /// Signatures are rebuilt from the buckets one chunk at a time, so this
+    /// needs neither the sqlite db nor a second copy of the index. Chunks
+    /// before the first one with a deleted slot are kept as they are.
+    /// Returns the number of reclaimed slots.
+    pub fn compact(&mut self) -> Result<usize, ReadOnlyError> {
+        if self.mapped.is_some() {
+            return Err(ReadOnlyError);
+        }
+        let deleted = self.deleted_count();
+        if deleted == 0 {
+            return Ok(0);
+        }
+
+        let first = (self.indexes.iter())
+            .position(ImageIndex::has_deleted)
+            .unwrap_or(self.indexes.len());
+        let start = first as u32 * CHUNK_SIZE;
+        let indexes = self.indexes.split_off(first);
+        let index_to_id = self.index_to_id.split_off(start as usize);
+        self.id_to_index.retain(|_, index| *index < start);
+        self.frames.retain(|_, frames| {
+            frames.retain(|&index| index < start);
+            !frames.is_empty()
+        });
+        self.frame_counts.clear();
+        for frames in self.frames.values() {
+            *self.frame_counts.entry(frames.len()).or_default() += 1;
+        }
+        for image_index in indexes {
+            let offset = image_index.offset();
+            for (i, sig) in image_index.signatures().into_iter().enumerate() {
+                let index = offset + i as u32;
+                if image_index.is_deleted(index) {
+                    continue;
+                }
+                let id = index_to_id[(index - start) as usize];
+                self.append(ImageData {
+                    id,
+                    avgl: sig.avgl,
+                    sig: sig.sig,
+                    metadata: image_index.as_ref().metadata(i),
+                });
+            }
+        }
+        Ok(deleted)
+    }
+
+    /// Adds an image, replacing any image with the same id along with its frames.
+    pub fn insert(&mut self, image: ImageData) -> Result<(), ReadOnlyError> {
+        if self.mapped.is_some() {
+            return Err(ReadOnlyError);
+        }
+        self.hide(image.id);
+        self.append(image);
+        Ok(())
+    }
+
+    /// Adds another frame to the image with the same id, or adds the image if it is
+    /// new. Queries match whichever frame of an image scores best.
+    pub fn insert_frame(&mut self, image: ImageData) -> Result<(), ReadOnlyError> {
+        if self.mapped.is_some() {
+            return Err(ReadOnlyError);
+        }
+        self.append(image);
+        Ok(())
+    }
+
+    /// Adds an image with several frames, the first one first, replacing any image
+    /// with the same id. See [`DB::insert_frame`].
+    pub fn insert_frames(
+        &mut self,
+        id: i64,
+        frames: &[Signature],
+        metadata: Metadata,
+    ) -> Result<(), ReadOnlyError> {
+        if self.mapped.is_some() {
+            return Err(ReadOnlyError);
+        }
+        self.hide(id);
+        for sig in frames {
+            self.append(ImageData {
+                id,
+                avgl: sig.avgl,
+                sig: sig.sig.clone(),
+                metadata,
+            });
+        }
+        Ok(())
+    }
+
+    /// Inserts many images at once, see [`DB::insert`].
+    pub fn insert_many(
+        &mut self,
+        images: impl IntoIterator<Item = ImageData>,
+    ) -> Result<(), ReadOnlyError> {
+        if self.mapped.is_some() {
+            return Err(ReadOnlyError);
+        }
+        let images = images.into_iter();
+        self.index_to_id.reserve(images.size_hint().0);
+        self.id_to_index.reserve(images.size_hint().0);
+        for image in images {
+            self.hide(image.id);
+            self.append(image);
+        }
+        Ok(())
     }
 
-    pub fn insert(&mut self, image: ImageData) {
+    /// Adds a slot for `image`, as another frame if its id is already present.
+    fn append(&mut self, image: ImageData) {
         let index = self.index_to_id.len() as u32;
         self.index_to_id.push(image.id);
-        self.id_to_index.insert(image.id, index);
+        let is_frame = match self.id_to_index.entry(image.id) {
+            Entry::Occupied(_) => true,
+            Entry::Vacant(entry) => {
+                entry.insert(index);
+                false
+            }
+        };
+        if is_frame {
+            let frames = self.frames.entry(image.id).or_default();
+            frames.push(index);
+            let count = frames.len();
+            self.recount_frames(count - 1, count);
+        }
         if self.indexes.is_empty() {
-            self.indexes.push(ImageIndex::new(0));
+            self.indexes.push(ImageIndex::new(0, self.kernel));
         }
         let mut image_index = self.indexes.last_mut().unwrap();
         if image_index.is_full() {
             println!("Images: {}", self.index_to_id.len());
-            self.indexes.push(ImageIndex::new(index));
+            self.indexes.push(ImageIndex::new(index, self.kernel));
             image_index = self.indexes.last_mut().unwrap();
         }
         let sig = Signature {
             avgl: image.avgl,
             sig: image.sig,
         };
-        image_index.append(index, sig)
+        image_index.append(index, sig, image.metadata)
     }
 
-    pub fn delete(&mut self, image: ImageData) {
+    /// Removes an image and all of its frames.
+    ///
+    /// `image` must hold the signature of the first frame.
+    pub fn delete(&mut self, image: ImageData) -> Result<(), ReadOnlyError> {
+        if self.mapped.is_some() {
+            return Err(ReadOnlyError);
+        }
         let sig = Signature {
             avgl: image.avgl,
             sig: image.sig,
         };
 
         let Some(index) = self.id_to_index.remove(&image.id) else {
-            return;
+            return Ok(());
         };
         let chunk_index = index / CHUNK_SIZE;
         if let Some(image_index) = self.indexes.get_mut(chunk_index as usize) {
             image_index.remove(index, sig);
         }
+        self.hide_frames(image.id);
+        Ok(())
+    }
+
+    /// Hides every slot of an image from queries, leaving its bucket entries
+    /// until the next compaction.
+    fn hide(&mut self, id: i64) {
+        if let Some(index) = self.id_to_index.remove(&id) {
+            self.mark_deleted(index);
+        }
+        self.hide_frames(id);
+    }
+
+    fn hide_frames(&mut self, id: i64) {
+        let frames = self.frames.remove(&id).unwrap_or_default();
+        self.recount_frames(frames.len(), 0);
+        for index in frames {
+            self.mark_deleted(index);
+        }
+    }
+
+    fn mark_deleted(&mut self, index: u32) {
+        let chunk_index = index / CHUNK_SIZE;
+        if let Some(image_index) = self.indexes.get_mut(chunk_index as usize) {
+            image_index.mark_deleted(index);
+        }
+    }
+
+    /// Moves an image from having `from` extra frames to having `to` in `frame_counts`.
+    fn recount_frames(&mut self, from: usize, to: usize) {
+        if from > 0 {
+            if let btree_map::Entry::Occupied(mut entry) = self.frame_counts.entry(from) {
+                *entry.get_mut() -= 1;
+                if *entry.get() == 0 {
+                    entry.remove();
+                }
+            }
+        }
+        if to > 0 {
+            *self.frame_counts.entry(to).or_default() += 1;
+        }
     }
 
     pub fn query(&self, sig: &Signature, limit: usize) -> Vec<(f32, i64)> {
+        self.query_with(sig, limit, &QueryOptions::default())
+    }
+
+    /// Runs several queries, each with its own limit, in parallel when possible.
+    pub fn query_many(
+        &self,
+        queries: &[(Signature, usize)],
+        options: &QueryOptions,
+    ) -> Vec<Vec<(f32, i64)>> {
+        let query = |(sig, limit): &(Signature, usize)| self.query_with(sig, *limit, options);
+        #[cfg(feature = "multi-thread")]
+        let results = queries.par_iter().map(query).collect();
+        #[cfg(not(feature = "multi-thread"))]
+        let results = queries.iter().map(query).collect();
+        results
+    }
+
+    /// Finds the images most similar to an image already in the database, using the
+    /// signature of its first frame stored in `sql_db`.
+    ///
+    /// Returns `None` if `id` is not indexed. With `exclude_self` the image itself is left
+    /// out of the results.
+    pub fn query_by_id(
+        &self,
+        sql_db: &SqlDB,
+        id: i64,
+        limit: usize,
+        options: &QueryOptions,
+        exclude_self: bool,
+    ) -> Option<Vec<(f32, i64)>> {
+        if !self.contains(id) {
+            return None;
+        }
+        let image = sql_db.get_many([id]).next()?;
+        Some(self.query_image(&image, limit, options, exclude_self))
+    }
+
+    /// Like [`DB::query_by_id`], with the image already fetched from the sqlite db.
+    pub fn query_image(
+        &self,
+        image: &ImageData,
+        limit: usize,
+        options: &QueryOptions,
+        exclude_self: bool,
+    ) -> Vec<(f32, i64)> {
+        let sig = Signature {
+            avgl: image.avgl,
+            sig: image.sig.clone(),
+        };
+        if !exclude_self {
+            return self.query_with(&sig, limit, options);
+        }
+        let mut result = self.query_with(&sig, limit.saturating_add(1), options);
+        result.retain(|&(_, other)| other != image.id);
+        result.truncate(limit);
+        result
+    }
+
+    /// Finds every pair of images that score at least `threshold` against each other.
+    ///
+    /// Every slot is scored against the whole index, so this costs one query per
+    /// image and frame. Signatures are rebuilt from the buckets one chunk at a time.
+    /// See [`DuplicateSearch`] to scan a changing index a batch at a time.
+    pub fn find_duplicates(&self, threshold: f32) -> Vec<Duplicate> {
+        self.find_duplicates_with_progress(threshold, |_, _| ())
+    }
+
+    /// Like [`DB::find_duplicates`], calling `progress` with the number of slots
+    /// done so far and the total number of slots.
+    pub fn find_duplicates_with_progress(
+        &self,
+        threshold: f32,
+        progress: impl Fn(usize, usize) + Sync,
+    ) -> Vec<Duplicate> {
+        let mut search = DuplicateSearch::new(threshold);
+        while search.step(self, CHUNK_SIZE as usize, &progress) {}
+        search.finish()
+    }
+
+    fn chunks(&self) -> (&[i64], Vec<ChunkRef<'_>>) {
+        match &self.mapped {
+            Some(mapped) => (mapped.index_to_id(), mapped.chunks(self.kernel).collect()),
+            None => (
+                &self.index_to_id,
+                self.indexes.iter().map(ImageIndex::as_ref).collect(),
+            ),
+        }
+    }
+
+    /// Like [`DB::query`], but only images whose [`Metadata`] match `filter` are ranked.
+    pub fn query_filtered(
+        &self,
+        sig: &Signature,
+        limit: usize,
+        filter: &Filter,
+    ) -> Vec<(f32, i64)> {
+        let options = QueryOptions {
+            filter: filter.clone(),
+            ..Default::default()
+        };
+        self.query_with(sig, limit, &options)
+    }
+
+    /// Like [`DB::query`], with custom score weights and filter.
+    pub fn query_with(
+        &self,
+        sig: &Signature,
+        limit: usize,
+        options: &QueryOptions,
+    ) -> Vec<(f32, i64)> {
         if limit == 0 {
             return Vec::new();
         }
-        let index_to_id = &self.index_to_id;
+        let (index_to_id, chunks) = self.chunks();
 
-        let query_index = |image_index: &ImageIndex| {
-            let scores = image_index.query(sig, limit);
+        // Every extra frame of an image can take a place in the top results of a
+        // chunk, but no more places than there are extra frames in the index.
+        let max_frames = self.max_frames();
+        let extra = limit.saturating_mul(max_frames - 1).min(self.frame_count());
+        let chunk_limit = limit.saturating_add(extra).min(CHUNK_SIZE as usize);
+        let query_index = |chunk: &ChunkRef| {
+            let scores = chunk.query(sig, chunk_limit, options);
             scores
                 .into_iter()
                 .map(|(score, index)| (score, index_to_id[index as usize]))
@@ -94,11 +622,15 @@ impl DB {
         };
 
         #[cfg(feature = "multi-thread")]
-        let mut all_scores: Vec<_> = self.indexes.par_iter().map(query_index).flatten().collect();
+        let mut all_scores: Vec<_> = chunks.par_iter().map(query_index).flatten().collect();
         #[cfg(not(feature = "multi-thread"))]
-        let mut all_scores: Vec<_> = self.indexes.iter().flat_map(query_index).collect();
+        let mut all_scores: Vec<_> = chunks.iter().flat_map(query_index).collect();
 
         all_scores.sort_by(|a, b| a.0.total_cmp(&b.0).then_with(|| a.1.cmp(&b.1)).reverse());
+        if max_frames > 1 {
+            let mut seen = HashSet::new();
+            all_scores.retain(|&(_, id)| seen.insert(id));
+        }
         all_scores.truncate(limit);
         all_scores
     }
@@ -107,6 +639,7 @@ impl DB {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testing::{self, ids, image};
 
     #[test]
     fn query() {
@@ -178,4 +711,190 @@ mod tests {
         let parsed: Signature = hash.parse().unwrap();
         assert_eq!(sig, parsed);
     }
+
+    #[test]
+    fn compact() {
+        // Images 1 to 30 share no coefficients. 5 and 7 have a second frame, and 5
+        // has metadata.
+        let mut images: Vec<_> = (1..=30).map(|id| image(id, id as i16 * 40)).collect();
+        images[4].metadata = Metadata {
+            tags: 1,
+            rating: b'q',
+            timestamp: 5,
+        };
+        images.push(ImageData {
+            metadata: images[4].metadata,
+            ..image(5, 2000)
+        });
+        images.push(image(7, 2040));
+        let mut db = DB::new(images);
+        for id in (3..=30).step_by(3) {
+            db.delete(image(id, id as i16 * 40)).unwrap();
+        }
+        assert_eq!(db.deleted_count(), 10);
+        let before = db.query(&testing::signature(200), 5);
+
+        assert_eq!(db.compact(), Ok(10));
+        assert_eq!(db.deleted_count(), 0);
+        assert_eq!(db.index_to_id.len(), 22);
+        assert_eq!((db.image_count(), db.frame_count()), (20, 2));
+        assert_eq!(db.query(&testing::signature(200), 5), before);
+        assert!(ids(db.query(&testing::signature(120), 30))
+            .iter()
+            .all(|id| id % 3 != 0));
+
+        // Frames and metadata are moved along with their images.
+        assert_eq!(ids(db.query(&testing::signature(2000), 1)), [5]);
+        assert_eq!(ids(db.query(&testing::signature(2040), 1)), [7]);
+        let tagged = Filter {
+            tags: 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            ids(db.query_filtered(&testing::signature(40), 5, &tagged)),
+            [5]
+        );
+        assert_eq!(db.compact(), Ok(0));
+    }
+
+    #[test]
+    fn compact_second_chunk() {
+        // Image 1 fills the first chunk with copies of itself, so only its second
+        // frame and images 2 and 3 land in the second chunk, where 2 gets deleted.
+        let mut images = vec![image(1, 1)];
+        images.extend((100..CHUNK_SIZE as i64 + 99).map(|id| image(id, 8000)));
+        images.extend([image(2, 41), image(1, 81), image(3, 121)]);
+        let mut db = DB::new(images);
+        db.delete(image(2, 41)).unwrap();
+
+        assert_eq!(db.compact(), Ok(1));
+        assert_eq!(db.index_to_id.len(), CHUNK_SIZE as usize + 2);
+        assert_eq!((db.frame_count(), db.max_frames()), (1, 2));
+        assert_eq!(ids(db.query(&testing::signature(81), 1)), [1]);
+        assert_eq!(ids(db.query(&testing::signature(121), 1)), [3]);
+        assert!(!db.contains(2));
+        db.delete(image(1, 1)).unwrap();
+        assert_eq!(db.compact(), Ok(2));
+        assert_eq!(
+            (db.image_count(), db.frame_count()),
+            (CHUNK_SIZE as usize, 0)
+        );
+    }
+
+    #[test]
+    fn frames() {
+        // Image 1 has three frames.
+        let mut db = DB::new([image(1, 1), image(2, 41), image(1, 81), image(1, 121)]);
+        assert_eq!(
+            (db.image_count(), db.frame_count(), db.max_frames()),
+            (2, 2, 3)
+        );
+        // Any frame matches, and an image is returned once however many frames match.
+        assert_eq!(ids(db.query(&testing::signature(121), 5)), [1, 2]);
+        assert_eq!(ids(db.query(&testing::signature(41), 5)), [2, 1]);
+        assert_eq!(ids(db.query(&testing::signature(1), 2)), [1, 2]);
+
+        // Frames are only added on request, and inserting an id replaces all its frames.
+        db.insert_frame(image(2, 161)).unwrap();
+        assert_eq!((db.frame_count(), db.max_frames()), (3, 3));
+        db.insert(image(1, 201)).unwrap();
+        assert_eq!(
+            (db.image_count(), db.frame_count(), db.max_frames()),
+            (2, 1, 2)
+        );
+        assert_eq!(ids(db.query(&testing::signature(201), 1)), [1]);
+        assert!(db.query(&testing::signature(121), 1)[0].0 < 1.);
+
+        let frames = [testing::signature(241), testing::signature(281)];
+        db.insert_frames(2, &frames, Metadata::default()).unwrap();
+        assert_eq!((db.frame_count(), db.max_frames()), (1, 2));
+        assert_eq!(ids(db.query(&testing::signature(281), 1)), [2]);
+        assert!(db.query(&testing::signature(161), 1)[0].0 < 1.);
+
+        db.delete(image(2, 241)).unwrap();
+        assert_eq!(
+            (db.image_count(), db.frame_count(), db.max_frames()),
+            (1, 0, 1)
+        );
+        assert_eq!(db.compact(), Ok(7));
+
+        // The frames of one image crowding the top of a chunk don't push others out.
+        let mut images: Vec<_> = (1..=4).map(|first| image(1, first)).collect();
+        images.extend((2..=4).map(|id| image(id, 3 + id as i16)));
+        let db = DB::new(images);
+        assert_eq!(ids(db.query(&testing::signature(1), 3)), [1, 2, 3]);
+    }
+
+    #[test]
+    fn query_many() {
+        // Image `k` shares `40 - k` coefficients with `testing::signature(1)`.
+        let db = DB::new((0..40).map(|k| image(k, 1 + k as i16)));
+        let queries = [
+            (testing::signature(1), 3),
+            (testing::signature(20), 0),
+            (testing::signature(39), 5),
+        ];
+        let results = db.query_many(&queries, &QueryOptions::default());
+        assert_eq!(results.len(), queries.len());
+        for ((sig, limit), result) in queries.iter().zip(&results) {
+            assert_eq!(result.len(), *limit);
+            assert_eq!(*result, db.query(sig, *limit));
+        }
+        assert_eq!(ids(results[0].clone()), [0, 1, 2]);
+
+        // Limits beyond the index size return every image.
+        let results = db.query_many(&[(testing::signature(1), usize::MAX)], &Default::default());
+        assert_eq!(results[0].len(), 40);
+    }
+
+    #[test]
+    fn insert_many() {
+        let mut sql_db = SqlDB::new(sqlite::open(":memory:").unwrap());
+        let images: Vec<_> = (1..=15).map(|id| image(id, id as i16 * 40)).collect();
+        assert_eq!(sql_db.insert_many(&images).unwrap().len(), 0);
+        let mut db = DB::new(sql_db.load());
+
+        // 11 to 15 get new signatures, and 16 to 20 are new.
+        let first = |id: i64| id as i16 * 40 + if id > 10 { 1000 } else { 0 };
+        let updates: Vec<_> = (11..=20).map(|id| image(id, first(id))).collect();
+        let replaced = sql_db.insert_many(&updates).unwrap();
+        let replaced_ids: Vec<_> = replaced.iter().map(|image| image.id).collect();
+        assert_eq!(replaced_ids, [11, 12, 13, 14, 15]);
+        assert_eq!(replaced[0].sig, images[10].sig);
+        for image in replaced {
+            db.delete(image).unwrap();
+        }
+        db.insert_many(updates).unwrap();
+
+        assert_eq!(db.image_count(), 20);
+        assert_eq!(sql_db.load().into_iter().count(), 20);
+        let reloaded = DB::new(sql_db.load());
+        for id in 1..=20 {
+            let sig = testing::signature(first(id));
+            assert_eq!(ids(db.query(&sig, 1)), [id]);
+            assert_eq!(ids(reloaded.query(&sig, 1)), [id]);
+        }
+        assert!(db.query(&testing::signature(440), 1)[0].0 < 1.);
+    }
+
+    #[test]
+    fn query_by_id() {
+        let mut sql_db = SqlDB::new(sqlite::open(":memory:").unwrap());
+        // Image `k` shares `40 - 4 * k` coefficients with image 0.
+        let images: Vec<_> = (0..10).map(|k| image(k, 1 + k as i16 * 4)).collect();
+        sql_db.insert_many(&images).unwrap();
+        let db = DB::new(sql_db.load());
+        let options = QueryOptions::default();
+
+        let result = db.query_by_id(&sql_db, 0, 5, &options, false).unwrap();
+        assert_eq!(result, db.query(&testing::signature(1), 5));
+        assert_eq!(ids(result), [0, 1, 2, 3, 4]);
+        let result = db.query_by_id(&sql_db, 0, 5, &options, true).unwrap();
+        assert_eq!(ids(result), [1, 2, 3, 4, 5]);
+        assert!(db.query_by_id(&sql_db, 1000, 5, &options, false).is_none());
+        let result = db
+            .query_by_id(&sql_db, 0, usize::MAX, &options, true)
+            .unwrap();
+        assert_eq!(result.len(), 9);
+    }
 }
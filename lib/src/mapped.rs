@@ -0,0 +1,203 @@
+use std::{fs::File, io, ops::Range, path::Path};
+
+use bytemuck::Pod;
+use memmap2::Mmap;
+
+use crate::{
+    bucket::{BucketRef, Packed},
+    index::{Buckets, ChunkRef, BUCKET_COUNT, CHUNK_SIZE},
+    kernel::Kernel,
+    snapshot::{invalid, Header, SectionCursor, HEADER_LEN},
+};
+
+struct MappedChunk {
+    offset: u32,
+    avgl_y: Range<usize>,
+    avgl_i: Range<usize>,
+    avgl_q: Range<usize>,
+    deleted: Range<usize>,
+    tags: Range<usize>,
+    ratings: Range<usize>,
+    timestamps: Range<usize>,
+    directory: Range<usize>,
+    pool: Range<usize>,
+}
+
+/// A snapshot served in place from a read-only memory map.
+pub(crate) struct MappedDB {
+    mmap: Mmap,
+    index_to_id: Range<usize>,
+    ids: Range<usize>,
+    frame_count: usize,
+    max_frames: usize,
+    /// Whether the buckets were checked to only hold ids of slots in their chunk.
+    verified: bool,
+    chunks: Vec<MappedChunk>,
+}
+
+impl MappedDB {
+    /// Maps `path`, only checking the header and that every section fits in the file.
+    ///
+    /// This touches a few pages per chunk, so it takes about the same time for any
+    /// snapshot size. Corrupt buckets give wrong results or panics, never unsafety.
+    pub(crate) fn open(path: &Path) -> io::Result<Self> {
+        Self::map(path, false)
+    }
+
+    /// Maps `path` and also checks the checksum, the id tables and every bucket,
+    /// which reads the whole file.
+    pub(crate) fn open_verified(path: &Path) -> io::Result<Self> {
+        Self::map(path, true)
+    }
+
+    fn map(path: &Path, verify: bool) -> io::Result<Self> {
+        let file = File::open(path)?;
+        // SAFETY: snapshots are only ever replaced by rename, never modified in place.
+        let mmap = unsafe { Mmap::map(&file)? };
+        let header = Header::from_bytes(&mmap)?;
+        if verify && crc32fast::hash(&mmap[HEADER_LEN as usize..]) != header.checksum {
+            return Err(invalid("snapshot checksum mismatch"));
+        }
+        if header.slots > header.chunks as u64 * CHUNK_SIZE as u64 || header.live > header.slots {
+            return Err(invalid("invalid snapshot header"));
+        }
+
+        let mut cursor = SectionCursor::new(&mmap);
+        let index_to_id = cursor.section::<i64>(header.slots)?;
+        let ids = cursor.section::<i64>(header.live)?;
+        let indexes = cursor.section::<u32>(header.live)?;
+        if verify {
+            let indexes: &[u32] = bytemuck::cast_slice(&mmap[indexes]);
+            if indexes.iter().any(|&index| index as u64 >= header.slots) {
+                return Err(invalid("invalid image slot"));
+            }
+            let sorted_ids: &[i64] = bytemuck::cast_slice(&mmap[ids.clone()]);
+            if sorted_ids.windows(2).any(|w| w[0] >= w[1]) {
+                return Err(invalid("unsorted image ids"));
+            }
+        }
+
+        let frame_count = cursor.u64()?;
+        if frame_count > header.slots {
+            return Err(invalid("invalid frame count"));
+        }
+        let frame_ids = cursor.section::<i64>(frame_count)?;
+        let frame_indexes = cursor.section::<u32>(frame_count)?;
+        let frame_ids: &[i64] = bytemuck::cast_slice(&mmap[frame_ids]);
+        if verify {
+            let frame_indexes: &[u32] = bytemuck::cast_slice(&mmap[frame_indexes]);
+            if frame_indexes
+                .iter()
+                .any(|&index| index as u64 >= header.slots)
+            {
+                return Err(invalid("invalid image slot"));
+            }
+            if frame_ids.windows(2).any(|w| w[0] > w[1]) {
+                return Err(invalid("unsorted frame ids"));
+            }
+        }
+        let max_frames = frame_ids
+            .chunk_by(|a, b| a == b)
+            .map(<[i64]>::len)
+            .max()
+            .unwrap_or(0)
+            + 1;
+
+        let mut chunks = Vec::with_capacity(header.chunks as usize);
+        let mut total_slots = 0;
+        for chunk in 0..header.chunks {
+            let offset = cursor.u32()?;
+            let total = cursor.u32()?;
+            let pool_len = cursor.u64()?;
+            let metadata_len = cursor.u64()?;
+            if offset != chunk * CHUNK_SIZE || total > CHUNK_SIZE {
+                return Err(invalid("invalid chunk header"));
+            }
+            if metadata_len != 0 && metadata_len != total as u64 {
+                return Err(invalid("invalid chunk header"));
+            }
+            if chunk + 1 < header.chunks && total != CHUNK_SIZE {
+                return Err(invalid("invalid chunk length"));
+            }
+            total_slots += total as u64;
+            let mapped = MappedChunk {
+                offset,
+                avgl_y: cursor.section::<f32>(total as u64)?,
+                avgl_i: cursor.section::<f32>(total as u64)?,
+                avgl_q: cursor.section::<f32>(total as u64)?,
+                deleted: cursor.section::<u64>(total.div_ceil(64) as u64)?,
+                tags: cursor.section::<u64>(metadata_len)?,
+                timestamps: cursor.section::<i64>(metadata_len)?,
+                ratings: cursor.section::<u8>(metadata_len)?,
+                directory: cursor.section::<[u32; 2]>(BUCKET_COUNT as u64)?,
+                pool: cursor.section::<Packed>(pool_len)?,
+            };
+            if verify {
+                let directory: &[[u32; 2]] = bytemuck::cast_slice(&mmap[mapped.directory.clone()]);
+                let pool: &[Packed] = bytemuck::cast_slice(&mmap[mapped.pool.clone()]);
+                for &entry in directory {
+                    BucketRef::decode(entry, pool, total as usize)
+                        .ok_or_else(|| invalid("invalid bucket"))?;
+                }
+            }
+            chunks.push(mapped);
+        }
+        if total_slots != header.slots {
+            return Err(invalid("invalid chunk length"));
+        }
+        cursor.finish()?;
+
+        Ok(Self {
+            mmap,
+            index_to_id,
+            ids,
+            frame_count: frame_count as usize,
+            max_frames,
+            verified: verify,
+            chunks,
+        })
+    }
+
+    fn slice<T: Pod>(&self, range: &Range<usize>) -> &[T] {
+        bytemuck::cast_slice(&self.mmap[range.clone()])
+    }
+
+    pub(crate) fn index_to_id(&self) -> &[i64] {
+        self.slice(&self.index_to_id)
+    }
+
+    pub(crate) fn contains(&self, id: i64) -> bool {
+        self.slice(&self.ids).binary_search(&id).is_ok()
+    }
+
+    pub(crate) fn image_count(&self) -> usize {
+        self.slice::<i64>(&self.ids).len()
+    }
+
+    pub(crate) fn frame_count(&self) -> usize {
+        self.frame_count
+    }
+
+    pub(crate) fn max_frames(&self) -> usize {
+        self.max_frames
+    }
+
+    pub(crate) fn chunks(&self, kernel: Kernel) -> impl Iterator<Item = ChunkRef<'_>> {
+        self.chunks.iter().map(move |chunk| ChunkRef {
+            offset: chunk.offset,
+            kernel,
+            avgl_y: self.slice(&chunk.avgl_y),
+            avgl_i: self.slice(&chunk.avgl_i),
+            avgl_q: self.slice(&chunk.avgl_q),
+            deleted: self.slice(&chunk.deleted),
+            tags: self.slice(&chunk.tags),
+            ratings: self.slice(&chunk.ratings),
+            timestamps: self.slice(&chunk.timestamps),
+            buckets: Buckets::Mapped {
+                directory: self.slice(&chunk.directory),
+                pool: self.slice(&chunk.pool),
+                verified: self.verified,
+            },
+        })
+    }
+}
@@ -0,0 +1,110 @@
+/// Optional per-image metadata that queries can be filtered on.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Metadata {
+    /// A set of small integer tags, tag `n` being bit `n`.
+    pub tags: u64,
+    /// A category or rating byte, such as `b's'`. `0` means unrated.
+    pub rating: u8,
+    /// Unix timestamp in seconds.
+    pub timestamp: i64,
+}
+
+impl Metadata {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Restricts query results by [`Metadata`] before the top results are picked.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Filter {
+    /// Tags every result must have.
+    pub tags: u64,
+    /// Tags no result may have.
+    pub exclude_tags: u64,
+    /// Accepted ratings, any if empty.
+    pub ratings: Vec<u8>,
+    /// Inclusive lower bound on the timestamp.
+    pub after: Option<i64>,
+    /// Exclusive upper bound on the timestamp.
+    pub before: Option<i64>,
+}
+
+impl Filter {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Whether `metadata` passes the filter.
+    pub fn matches(&self, metadata: &Metadata) -> bool {
+        metadata.tags & self.tags == self.tags
+            && metadata.tags & self.exclude_tags == 0
+            && (self.ratings.is_empty() || self.ratings.contains(&metadata.rating))
+            && self.after.is_none_or(|after| metadata.timestamp >= after)
+            && self.before.is_none_or(|before| metadata.timestamp < before)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::{image, signature},
+        ImageData, DB,
+    };
+
+    /// Images 1 to 12 sharing no coefficients, with tag `id % 4`, rating `sqe[id % 3]`
+    /// and timestamp `id`.
+    fn tagged() -> DB {
+        DB::new((1..=12).map(|id| ImageData {
+            metadata: Metadata {
+                tags: 1 << (id % 4),
+                rating: b"sqe"[id as usize % 3],
+                timestamp: id,
+            },
+            ..image(id, id as i16 * 40)
+        }))
+    }
+
+    #[test]
+    fn filter() {
+        let db = tagged();
+        let sig = signature(6 * 40);
+        let ids = |filter: &Filter, limit| {
+            let result = db.query_filtered(&sig, limit, filter);
+            let mut ids: Vec<_> = result.into_iter().map(|(_, id)| id).collect();
+            ids.sort_unstable();
+            ids
+        };
+        let tags = Filter {
+            tags: 1 << 1,
+            ..Default::default()
+        };
+        assert_eq!(ids(&tags, 12), [1, 5, 9]);
+        let excluded = Filter {
+            exclude_tags: 1 << 0 | 1 << 2,
+            ratings: vec![b's', b'e'],
+            ..Default::default()
+        };
+        assert_eq!(ids(&excluded, 12), [3, 5, 9, 11]);
+        let dated = Filter {
+            after: Some(4),
+            before: Some(8),
+            ..Default::default()
+        };
+        assert_eq!(ids(&dated, 12), [4, 5, 6, 7]);
+        // Filtered before the top results are picked, so the limit is still filled.
+        assert_eq!(ids(&tags, 2).len(), 2);
+        assert_eq!(db.query_filtered(&sig, 1, &dated)[0].1, 6);
+
+        assert!(Filter::default().is_empty());
+        assert!(!dated.is_empty());
+        let metadata = Metadata {
+            tags: 0b110,
+            rating: b'q',
+            timestamp: 7,
+        };
+        assert!(dated.matches(&metadata));
+        assert!(!excluded.matches(&metadata));
+    }
+}
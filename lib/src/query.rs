@@ -0,0 +1,122 @@
+use std::str::FromStr;
+
+use crate::metadata::Filter;
+
+/// Score weights, one row per coefficient band and one column per color channel (Y, I, Q).
+///
+/// Row 0 weighs the difference in average color. Rows 1 to 5 weigh matching coefficients,
+/// by how far they are from the top left of the transform.
+pub type Weights = [[f32; 3]; 6];
+
+/// The weight tables of the original iqdb.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WeightProfile {
+    /// Tuned for scanned photos.
+    #[default]
+    Photo,
+    /// Tuned for hand-drawn and painted images, such as line art and manga scans.
+    Sketch,
+}
+
+impl WeightProfile {
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Photo => "photo",
+            Self::Sketch => "sketch",
+        }
+    }
+
+    /// The profile whose weights are exactly `weights`, if any.
+    pub fn of(weights: &Weights) -> Option<Self> {
+        [Self::Photo, Self::Sketch]
+            .into_iter()
+            .find(|profile| profile.weights() == *weights)
+    }
+
+    pub const fn weights(self) -> Weights {
+        match self {
+            Self::Photo => [
+                [5.00, 19.21, 34.37],
+                [0.83, 1.26, 0.36],
+                [1.01, 0.44, 0.45],
+                [0.52, 0.53, 0.14],
+                [0.47, 0.28, 0.18],
+                [0.30, 0.14, 0.27],
+            ],
+            Self::Sketch => [
+                [4.04, 15.14, 22.62],
+                [0.78, 0.92, 0.40],
+                [0.46, 0.53, 0.63],
+                [0.42, 0.26, 0.25],
+                [0.41, 0.14, 0.15],
+                [0.32, 0.07, 0.38],
+            ],
+        }
+    }
+}
+
+impl FromStr for WeightProfile {
+    type Err = ();
+
+    /// Parses `photo` or `sketch`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "photo" => Ok(Self::Photo),
+            "sketch" => Ok(Self::Sketch),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Which color channels a query compares.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum QueryMode {
+    #[default]
+    Color,
+    /// Only luminance, so that grayscale scans can match colored originals.
+    /// Scores are scaled to the luminance weights alone, so a match still scores 100.
+    Grayscale,
+}
+
+impl QueryMode {
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Color => "color",
+            Self::Grayscale => "grayscale",
+        }
+    }
+}
+
+impl FromStr for QueryMode {
+    type Err = ();
+
+    /// Parses `color` or `grayscale`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "color" => Ok(Self::Color),
+            "grayscale" => Ok(Self::Grayscale),
+            _ => Err(()),
+        }
+    }
+}
+
+/// How a query scores and picks its results.
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueryOptions {
+    pub weights: Weights,
+    pub mode: QueryMode,
+    pub filter: Filter,
+    /// The lowest score a result may have, from 0 to 100.
+    pub min_score: Option<f32>,
+}
+
+impl Default for QueryOptions {
+    fn default() -> Self {
+        Self {
+            weights: WeightProfile::Photo.weights(),
+            mode: QueryMode::default(),
+            filter: Filter::default(),
+            min_score: None,
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use std::fmt::Display;
+
+use image::DynamicImage;
+
+use crate::haar::{trim_bounds, Signature, SignatureOptions};
+
+/// A part of an image hashed on its own, so that crops of it can still be found.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Region {
+    /// The middle two thirds in both directions.
+    Center,
+    /// Everything but a tenth on each side, for reposts with added borders.
+    Inset,
+    /// The left two thirds.
+    Left,
+    /// The right two thirds.
+    Right,
+    /// The top two thirds.
+    Top,
+    /// The bottom two thirds.
+    Bottom,
+}
+
+impl Region {
+    pub const ALL: [Self; 6] = [
+        Self::Center,
+        Self::Inset,
+        Self::Left,
+        Self::Right,
+        Self::Top,
+        Self::Bottom,
+    ];
+
+    /// The left, top, right and bottom edges as fractions of the image size.
+    fn edges(self) -> [f64; 4] {
+        const THIRD: f64 = 1. / 3.;
+        const SIXTH: f64 = 1. / 6.;
+        match self {
+            Self::Center => [SIXTH, SIXTH, 1. - SIXTH, 1. - SIXTH],
+            Self::Inset => [0.1, 0.1, 0.9, 0.9],
+            Self::Left => [0., 0., 1. - THIRD, 1.],
+            Self::Right => [THIRD, 0., 1., 1.],
+            Self::Top => [0., 0., 1., 1. - THIRD],
+            Self::Bottom => [0., THIRD, 1., 1.],
+        }
+    }
+
+    /// The `(x, y, width, height)` of this region of a `width` by `height` image,
+    /// never empty unless the image is.
+    pub fn bounds(self, width: u32, height: u32) -> (u32, u32, u32, u32) {
+        let [left, top, right, bottom] = self.edges();
+        let x = (left * width as f64) as u32;
+        let y = (top * height as f64) as u32;
+        let w = ((right * width as f64) as u32).saturating_sub(x).max(1);
+        let h = ((bottom * height as f64) as u32).saturating_sub(y).max(1);
+        (x.min(width), y.min(height), w.min(width), h.min(height))
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Center => "center",
+            Self::Inset => "inset",
+            Self::Left => "left",
+            Self::Right => "right",
+            Self::Top => "top",
+            Self::Bottom => "bottom",
+        }
+    }
+}
+
+impl Display for Region {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl Signature {
+    /// Hashes each of [`Region::ALL`] of an image separately.
+    ///
+    /// Borders are trimmed from the whole image first, not from each region.
+    pub fn from_image_regions(
+        img: &DynamicImage,
+        options: &SignatureOptions,
+    ) -> Vec<(Region, Signature)> {
+        let (left, top, width, height) = match options.trim {
+            Some(tolerance) => trim_bounds(img, tolerance),
+            None => (0, 0, img.width(), img.height()),
+        };
+        let options = SignatureOptions {
+            trim: None,
+            ..options.clone()
+        };
+        Region::ALL
+            .into_iter()
+            .map(|region| {
+                let (x, y, w, h) = region.bounds(width, height);
+                let crop = img.crop_imm(left + x, top + y, w, h);
+                (region, Signature::from_image_with(&crop, &options))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing::pattern, SqlDB, DB};
+
+    #[test]
+    fn regions() {
+        let img = pattern(120, 90, 3);
+        let options = SignatureOptions::default();
+        let mut sql_db = SqlDB::new(sqlite::open(":memory:").unwrap());
+        for (id, img) in [(1, &img), (2, &pattern(120, 90, 4))] {
+            let regions = Signature::from_image_regions(img, &options);
+            assert_eq!(regions.len(), Region::ALL.len());
+            sql_db.insert_regions(id, &regions).unwrap();
+        }
+        let mut db = DB::new(sql_db.load_regions());
+        assert_eq!(db.image_count(), 2);
+        assert_eq!(db.frame_count(), 2 * (Region::ALL.len() - 1));
+
+        let (x, y, w, h) = Region::Right.bounds(img.width(), img.height());
+        let crop = Signature::from_image(&img.crop_imm(x, y, w, h));
+        let result = db.query(&crop, 2);
+        assert_eq!(result[0].1, 1);
+        assert!(result[0].0 > 99.);
+
+        let first = sql_db.delete_regions(1).unwrap().unwrap();
+        db.delete(first).unwrap();
+        assert!(!db.contains(1));
+        assert_eq!(db.query(&crop, 2).len(), 1);
+        assert!(sql_db.load_regions().all(|image| image.id == 2));
+    }
+}
@@ -0,0 +1,355 @@
+//! Binary snapshot of a [`DB`](crate::DB).
+//!
+//! All values are little-endian and every section starts on an 8 byte boundary,
+//! so the file can be mapped into memory and read in place.
+//!
+//! ```text
+//! header       magic, version, chunk count, slot count, live count, crc32 of the body
+//! index_to_id  [i64; slots]
+//! live ids     [i64; live] sorted, followed by their slots [u32; live]
+//! frames       frame count, then the ids [i64; frames] and slots [u32; frames]
+//!              of the extra frames of animated images, sorted by id and slot
+//! chunks       chunk header, avgl_y, avgl_i, avgl_q, deleted bitmap, metadata tags,
+//!              timestamps and ratings (empty when unused), bucket directory, bucket pool
+//! ```
+
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    path::Path,
+};
+
+use bytemuck::Pod;
+
+pub(crate) const MAGIC: [u8; 8] = *b"IQDBSNAP";
+pub(crate) const VERSION: u32 = 4;
+pub(crate) const HEADER_LEN: u64 = 40;
+const ALIGN: u64 = 8;
+
+pub(crate) fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Header {
+    pub(crate) chunks: u32,
+    pub(crate) slots: u64,
+    pub(crate) live: u64,
+    pub(crate) checksum: u32,
+}
+
+impl Header {
+    fn to_bytes(self) -> [u8; HEADER_LEN as usize] {
+        let mut bytes = [0; HEADER_LEN as usize];
+        bytes[0..8].copy_from_slice(&MAGIC);
+        bytes[8..12].copy_from_slice(&VERSION.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.chunks.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.slots.to_le_bytes());
+        bytes[24..32].copy_from_slice(&self.live.to_le_bytes());
+        bytes[32..36].copy_from_slice(&self.checksum.to_le_bytes());
+        bytes
+    }
+
+    pub(crate) fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        if !cfg!(target_endian = "little") {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "snapshots require a little-endian host",
+            ));
+        }
+        if bytes.len() < HEADER_LEN as usize || bytes[0..8] != MAGIC {
+            return Err(invalid("not an iqdb snapshot"));
+        }
+        let u32_at = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        let u64_at = |i: usize| u64::from_le_bytes(bytes[i..i + 8].try_into().unwrap());
+        if u32_at(8) != VERSION {
+            return Err(invalid("unsupported snapshot version"));
+        }
+        Ok(Self {
+            chunks: u32_at(12),
+            slots: u64_at(16),
+            live: u64_at(24),
+            checksum: u32_at(32),
+        })
+    }
+}
+
+fn padding_len(position: u64) -> usize {
+    ((ALIGN - position % ALIGN) % ALIGN) as usize
+}
+
+pub(crate) struct SnapshotWriter {
+    inner: BufWriter<File>,
+    hasher: crc32fast::Hasher,
+    position: u64,
+    header: Header,
+}
+
+impl SnapshotWriter {
+    pub(crate) fn create(path: &Path, header: Header) -> io::Result<Self> {
+        if !cfg!(target_endian = "little") {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "snapshots require a little-endian host",
+            ));
+        }
+        let mut inner = BufWriter::new(File::create(path)?);
+        inner.write_all(&header.to_bytes())?;
+        Ok(Self {
+            inner,
+            hasher: crc32fast::Hasher::new(),
+            position: HEADER_LEN,
+            header,
+        })
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.inner.write_all(bytes)?;
+        self.hasher.update(bytes);
+        self.position += bytes.len() as u64;
+        Ok(())
+    }
+
+    pub(crate) fn u32(&mut self, value: u32) -> io::Result<()> {
+        self.write_bytes(&value.to_le_bytes())
+    }
+
+    pub(crate) fn u64(&mut self, value: u64) -> io::Result<()> {
+        self.write_bytes(&value.to_le_bytes())
+    }
+
+    /// Writes `values` and pads the section to the next 8 byte boundary.
+    pub(crate) fn slice<T: Pod>(&mut self, values: &[T]) -> io::Result<()> {
+        self.write_bytes(bytemuck::cast_slice(values))?;
+        let padding = [0; ALIGN as usize];
+        self.write_bytes(&padding[..padding_len(self.position)])
+    }
+
+    /// Fills in the checksum and flushes everything to disk.
+    pub(crate) fn finish(mut self) -> io::Result<()> {
+        self.header.checksum = self.hasher.finalize();
+        let mut file = self.inner.into_inner().map_err(|e| e.into_error())?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&self.header.to_bytes())?;
+        file.sync_all()
+    }
+}
+
+pub(crate) struct SnapshotReader {
+    inner: BufReader<File>,
+    hasher: crc32fast::Hasher,
+    position: u64,
+    len: u64,
+    header: Header,
+}
+
+impl SnapshotReader {
+    pub(crate) fn open(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len();
+        let mut inner = BufReader::with_capacity(1 << 20, file);
+        let mut bytes = [0; HEADER_LEN as usize];
+        inner
+            .read_exact(&mut bytes)
+            .map_err(|_| invalid("not an iqdb snapshot"))?;
+        let header = Header::from_bytes(&bytes)?;
+        Ok(Self {
+            inner,
+            hasher: crc32fast::Hasher::new(),
+            position: HEADER_LEN,
+            len,
+            header,
+        })
+    }
+
+    pub(crate) fn header(&self) -> Header {
+        self.header
+    }
+
+    fn read_bytes(&mut self, bytes: &mut [u8]) -> io::Result<()> {
+        self.inner
+            .read_exact(bytes)
+            .map_err(|_| invalid("truncated snapshot"))?;
+        self.hasher.update(bytes);
+        self.position += bytes.len() as u64;
+        Ok(())
+    }
+
+    pub(crate) fn u32(&mut self) -> io::Result<u32> {
+        let mut bytes = [0; 4];
+        self.read_bytes(&mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub(crate) fn u64(&mut self) -> io::Result<u64> {
+        let mut bytes = [0; 8];
+        self.read_bytes(&mut bytes)?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    /// Reads `len` values written by [`SnapshotWriter::slice`].
+    pub(crate) fn vec<T: Pod>(&mut self, len: u64) -> io::Result<Vec<T>> {
+        let size = len.saturating_mul(std::mem::size_of::<T>() as u64);
+        if size > self.len - self.position {
+            return Err(invalid("truncated snapshot"));
+        }
+        let mut values = vec![T::zeroed(); len as usize];
+        self.read_bytes(bytemuck::cast_slice_mut(&mut values))?;
+        let mut padding = [0; ALIGN as usize];
+        let padding = &mut padding[..padding_len(self.position)];
+        self.read_bytes(padding)?;
+        Ok(values)
+    }
+
+    /// Verifies the checksum once the whole body has been read.
+    pub(crate) fn finish(self) -> io::Result<()> {
+        if self.position != self.len {
+            return Err(invalid("trailing data in snapshot"));
+        }
+        if self.hasher.finalize() != self.header.checksum {
+            return Err(invalid("snapshot checksum mismatch"));
+        }
+        Ok(())
+    }
+}
+
+/// Walks the sections of a snapshot that is already in memory, without copying them.
+pub(crate) struct SectionCursor<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> SectionCursor<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Self {
+        Self {
+            bytes,
+            position: HEADER_LEN as usize,
+        }
+    }
+
+    fn take(&mut self, len: u64) -> io::Result<Range<usize>> {
+        let remaining = self.bytes.len() - self.position;
+        if len > remaining as u64 {
+            return Err(invalid("truncated snapshot"));
+        }
+        let range = self.position..self.position + len as usize;
+        self.position = range.end;
+        Ok(range)
+    }
+
+    pub(crate) fn u32(&mut self) -> io::Result<u32> {
+        let range = self.take(4)?;
+        Ok(u32::from_le_bytes(self.bytes[range].try_into().unwrap()))
+    }
+
+    pub(crate) fn u64(&mut self) -> io::Result<u64> {
+        let range = self.take(8)?;
+        Ok(u64::from_le_bytes(self.bytes[range].try_into().unwrap()))
+    }
+
+    /// Returns the byte range of `len` values written by [`SnapshotWriter::slice`].
+    pub(crate) fn section<T: Pod>(&mut self, len: u64) -> io::Result<Range<usize>> {
+        let range = self.take(len.saturating_mul(std::mem::size_of::<T>() as u64))?;
+        bytemuck::try_cast_slice::<u8, T>(&self.bytes[range.clone()])
+            .map_err(|_| invalid("misaligned snapshot section"))?;
+        self.take(padding_len(self.position as u64) as u64)?;
+        Ok(range)
+    }
+
+    pub(crate) fn finish(self) -> io::Result<()> {
+        if self.position != self.bytes.len() {
+            return Err(invalid("trailing data in snapshot"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        index::CHUNK_SIZE,
+        testing::{ids, image, signature},
+        Filter, ImageData, Metadata, ReadOnlyError, DB,
+    };
+
+    /// The signature of filler image `id`, one of four that overlap by 30 coefficients.
+    fn filler(id: i64) -> i16 {
+        8000 + (id % 4) as i16 * 10
+    }
+
+    #[test]
+    fn round_trip() {
+        // Enough filler to spill into a second chunk, with more than a mask's worth of
+        // slots in both, so the shared coefficients are stored as masks in each chunk.
+        // Image 4 and a second frame of image 3 land in the second chunk. Image 2 is
+        // rated, and image 1 and a filler image in the second chunk get deleted.
+        let mut images = vec![
+            image(1, 1),
+            ImageData {
+                metadata: Metadata {
+                    rating: b'e',
+                    ..Default::default()
+                },
+                ..image(2, 41)
+            },
+            image(3, 81),
+        ];
+        let fillers = 100..CHUNK_SIZE as i64 + 5100;
+        images.extend(fillers.map(|id| image(id, filler(id))));
+        images.push(image(4, 121));
+        images.push(image(3, 161));
+        let mut db = DB::new(images);
+        let deleted = CHUNK_SIZE as i64 + 4100;
+        db.delete(image(1, 1)).unwrap();
+        db.delete(image(deleted, filler(deleted))).unwrap();
+        let path = std::env::temp_dir().join(format!("iqdb-{}.snapshot", std::process::id()));
+        db.save_snapshot(&path).unwrap();
+
+        let rated = Filter {
+            ratings: vec![b'e'],
+            ..Default::default()
+        };
+        let check = |loaded: &DB| {
+            assert_eq!(loaded.image_count(), db.image_count());
+            assert_eq!(loaded.deleted_count(), 2);
+            assert_eq!(loaded.frame_count(), 1);
+            assert!(!loaded.contains(1));
+            assert!(loaded.contains(2));
+            assert_eq!(ids(loaded.query(&signature(121), 1)), [4]);
+            assert_eq!(ids(loaded.query(&signature(161), 1)), [3]);
+            assert_eq!(loaded.query(&signature(1), 3), db.query(&signature(1), 3));
+            for first in [8000, 8030] {
+                let result = loaded.query(&signature(first), 20);
+                assert_eq!(result, db.query(&signature(first), 20));
+                assert!(ids(result).iter().all(|&id| id != deleted));
+            }
+            let result = ids(loaded.query(&signature(8000), 5_000));
+            assert!(result.iter().all(|&id| id % 4 == 0 && id != deleted));
+            assert!(result.iter().any(|&id| id > CHUNK_SIZE as i64));
+            assert_eq!(ids(loaded.query_filtered(&signature(8000), 5, &rated)), [2]);
+        };
+        let mut loaded = DB::load_snapshot(&path).unwrap();
+        check(&loaded);
+        loaded.insert(image(5, 201)).unwrap();
+        assert_eq!(ids(loaded.query(&signature(201), 1)), [5]);
+
+        {
+            let mut mapped = DB::open_snapshot(&path).unwrap();
+            assert!(mapped.is_read_only());
+            check(&mapped);
+            assert_eq!(mapped.insert(image(5, 201)), Err(ReadOnlyError));
+            assert_eq!(mapped.delete(image(2, 41)), Err(ReadOnlyError));
+            assert!(mapped.save_snapshot(&path).is_err());
+        }
+        check(&DB::open_snapshot_verified(&path).unwrap());
+
+        let mut bytes = std::fs::read(&path).unwrap();
+        let middle = bytes.len() / 2;
+        bytes[middle] ^= 1;
+        std::fs::write(&path, &bytes).unwrap();
+        assert!(DB::load_snapshot(&path).is_err());
+        assert!(DB::open_snapshot_verified(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
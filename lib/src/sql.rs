@@ -1,10 +1,22 @@
-use crate::Signature;
+use std::collections::HashMap;
+
+use crate::{Metadata, Region, Resampling, Signature};
 
 #[derive(Clone, Debug)]
 pub struct ImageData {
     pub id: i64,
     pub avgl: (f64, f64, f64),
     pub sig: Vec<i16>,
+    pub metadata: Metadata,
+}
+
+/// Parses a row of the `images` table in the current schema.
+impl TryFrom<Vec<sqlite::Value>> for ImageData {
+    type Error = ();
+
+    fn try_from(values: Vec<sqlite::Value>) -> Result<Self, Self::Error> {
+        SqlDB::parse_columns(values)
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -15,6 +27,14 @@ pub enum SqlSchema {
 
 pub struct SqlDB {
     schema: SqlSchema,
+    /// Whether the `frames` table holding the extra frames of animations exists.
+    frames: bool,
+    /// Whether the `metadata` table exists.
+    metadata: bool,
+    /// Whether the `regions` table holding the signatures of parts of images exists.
+    regions: bool,
+    /// Whether the `settings` table, holding how the signatures were computed, exists.
+    settings: bool,
     connection: sqlite::Connection,
 }
 
@@ -51,19 +71,156 @@ impl SqlDB {
         }
         let schema = schema.unwrap();
         dbg!(schema);
-        Self { schema, connection }
+
+        let create = "
+        CREATE TABLE IF NOT EXISTS 'frames'
+        (
+            'id' INTEGER NOT NULL , 'frame' INTEGER NOT NULL ,
+            'avglf1' REAL NOT NULL , 'avglf2' REAL NOT NULL , 'avglf3' REAL NOT NULL ,
+            'sig' BLOB NOT NULL ,
+            PRIMARY KEY ('id', 'frame')
+        );
+        CREATE TABLE IF NOT EXISTS 'metadata'
+        (
+            'id' INTEGER PRIMARY KEY NOT NULL ,
+            'tags' INTEGER NOT NULL , 'rating' INTEGER NOT NULL , 'timestamp' INTEGER NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS 'regions'
+        (
+            'id' INTEGER NOT NULL , 'region' INTEGER NOT NULL ,
+            'avglf1' REAL NOT NULL , 'avglf2' REAL NOT NULL , 'avglf3' REAL NOT NULL ,
+            'sig' BLOB NOT NULL ,
+            PRIMARY KEY ('id', 'region')
+        );
+        CREATE TABLE IF NOT EXISTS 'settings'
+        (
+            'name' TEXT PRIMARY KEY NOT NULL , 'value' TEXT NOT NULL
+        )";
+        // Read-only connections keep working without the tables.
+        let _ = connection.execute(create);
+        let table_exists = |name: &str| {
+            let query = format!("SELECT 1 FROM sqlite_master WHERE name='{name}'");
+            connection
+                .prepare(query)
+                .unwrap()
+                .into_iter()
+                .next()
+                .is_some()
+        };
+        let frames = table_exists("frames");
+        let metadata = table_exists("metadata");
+        let regions = table_exists("regions");
+        let settings = table_exists("settings");
+        Self {
+            schema,
+            frames,
+            metadata,
+            regions,
+            settings,
+            connection,
+        }
+    }
+
+    /// Whether no image is stored.
+    pub fn is_empty(&self) -> Result<bool, sqlite::Error> {
+        let mut statement = self.connection.prepare("SELECT 1 FROM images LIMIT 1")?;
+        Ok(statement.next()? == sqlite::State::Done)
+    }
+
+    /// The name of the [`Resampling`] the stored signatures were computed with, if
+    /// it was recorded.
+    pub fn resampling(&self) -> Result<Option<String>, sqlite::Error> {
+        if !self.settings {
+            return Ok(None);
+        }
+        let mut statement = self
+            .connection
+            .prepare("SELECT value FROM settings WHERE name = 'resampling'")?;
+        if statement.next()? == sqlite::State::Done {
+            return Ok(None);
+        }
+        Ok(Some(statement.read::<String, _>(0)?))
+    }
+
+    /// Records the [`Resampling`] the stored signatures are computed with.
+    pub fn set_resampling(&self, resampling: Resampling) -> Result<(), sqlite::Error> {
+        let query = "INSERT OR REPLACE INTO settings (name, value) VALUES ('resampling', ?)";
+        let mut statement = self.connection.prepare(query)?;
+        statement.bind((1, resampling.name()))?;
+        statement.next()?;
+        Ok(())
+    }
+
+    fn load_metadata(&self, ids: Option<&[String]>) -> HashMap<i64, Metadata> {
+        if !self.metadata {
+            return HashMap::new();
+        }
+        let query = match ids {
+            Some(ids) => format!("SELECT * FROM metadata WHERE id IN ({})", ids.join(", ")),
+            None => "SELECT * FROM metadata".to_string(),
+        };
+        self.connection
+            .prepare(query)
+            .unwrap()
+            .into_iter()
+            .map(|row| {
+                let row = row.unwrap();
+                let metadata = Metadata {
+                    tags: row.read::<i64, _>("tags") as u64,
+                    rating: row.read::<i64, _>("rating") as u8,
+                    timestamp: row.read::<i64, _>("timestamp"),
+                };
+                (row.read::<i64, _>("id"), metadata)
+            })
+            .collect()
+    }
+
+    /// Stores the metadata of an image, replacing any previous one.
+    pub fn set_metadata(&self, id: i64, metadata: &Metadata) -> Result<(), sqlite::Error> {
+        let query = "INSERT OR REPLACE INTO metadata (id, tags, rating, timestamp)
+            VALUES (:id, :tags, :rating, :timestamp)";
+        let mut statement = self.connection.prepare(query)?;
+        statement.bind::<&[(_, sqlite::Value)]>(
+            &[
+                (":id", id.into()),
+                (":tags", (metadata.tags as i64).into()),
+                (":rating", (metadata.rating as i64).into()),
+                (":timestamp", metadata.timestamp.into()),
+            ][..],
+        )?;
+        if let Some(Err(error)) = statement.into_iter().next() {
+            return Err(error);
+        }
+        Ok(())
     }
 
+    /// Loads every image, followed by the extra frames of animated ones.
     pub fn load(&self) -> impl IntoIterator<Item = ImageData> + '_ {
+        let metadata = self.load_metadata(None);
         let query = "SELECT * FROM images";
-        self.connection
+        let images = self
+            .connection
             .prepare(query)
             .unwrap()
             .into_iter()
             .map(|row| {
                 let values: Vec<sqlite::Value> = row.unwrap().into();
                 self.parse(values).unwrap()
-            })
+            });
+        let query = "SELECT id, avglf1, avglf2, avglf3, sig FROM frames ORDER BY id, frame";
+        let frames = self
+            .frames
+            .then(|| self.connection.prepare(query).unwrap())
+            .into_iter()
+            .flatten()
+            .map(|row| {
+                let values: Vec<sqlite::Value> = row.unwrap().into();
+                Self::parse_columns(values).unwrap()
+            });
+        images.chain(frames).map(move |mut image| {
+            image.metadata = metadata.get(&image.id).copied().unwrap_or_default();
+            image
+        })
     }
 
     pub fn get_many(
@@ -75,13 +232,16 @@ impl SqlDB {
             SqlSchema::V1 => format!("SELECT * FROM images WHERE post_id IN ({})", ids.join(", ")),
             SqlSchema::V2 => format!("SELECT * FROM images WHERE id IN ({})", ids.join(", ")),
         };
+        let metadata = self.load_metadata(Some(&ids));
         self.connection
             .prepare(query)
             .unwrap()
             .into_iter()
-            .map(|row| {
+            .map(move |row| {
                 let values: Vec<sqlite::Value> = row.unwrap().into();
-                self.parse(values).unwrap()
+                let mut image = self.parse(values).unwrap();
+                image.metadata = metadata.get(&image.id).copied().unwrap_or_default();
+                image
             })
     }
 
@@ -117,7 +277,174 @@ impl SqlDB {
         Ok(())
     }
 
+    /// Inserts the first frame into `images` and the remaining ones into `frames`.
+    pub fn insert_frames(&self, id: i64, frames: &[Signature]) -> Result<(), sqlite::Error> {
+        let Some((first, rest)) = frames.split_first() else {
+            return Ok(());
+        };
+        self.transaction(|| {
+            self.insert(id, first)?;
+            let query = "INSERT INTO frames (id, frame, avglf1, avglf2, avglf3, sig)
+                VALUES (:id, :frame, :avglf1, :avglf2, :avglf3, :sig)";
+            for (frame, sig) in rest.iter().enumerate() {
+                let sig_bytes: Vec<u8> = sig.sig.iter().flat_map(|i| i.to_le_bytes()).collect();
+                let mut statement = self.connection.prepare(query)?;
+                statement.bind::<&[(_, sqlite::Value)]>(
+                    &[
+                        (":id", id.into()),
+                        (":frame", (frame as i64 + 1).into()),
+                        (":avglf1", sig.avgl.0.into()),
+                        (":avglf2", sig.avgl.1.into()),
+                        (":avglf3", sig.avgl.2.into()),
+                        (":sig", sig_bytes.into()),
+                    ][..],
+                )?;
+                if let Some(Err(error)) = statement.into_iter().next() {
+                    return Err(error);
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// The number of frames stored for an image, counting the first one.
+    pub fn frames_of(&self, id: i64) -> Result<usize, sqlite::Error> {
+        if !self.frames {
+            return Ok(1);
+        }
+        let mut statement = self
+            .connection
+            .prepare("SELECT COUNT(*) FROM frames WHERE id = ?")?;
+        statement.bind((1, id))?;
+        statement.next()?;
+        Ok(statement.read::<i64, _>(0)? as usize + 1)
+    }
+
+    /// Loads the region signatures of every image, grouped by image. Each region
+    /// is returned under the id of its image.
+    pub fn load_regions(&self) -> impl Iterator<Item = ImageData> + '_ {
+        let query = "SELECT id, avglf1, avglf2, avglf3, sig FROM regions ORDER BY id, region";
+        self.regions
+            .then(|| self.connection.prepare(query).unwrap())
+            .into_iter()
+            .flatten()
+            .map(|row| {
+                let values: Vec<sqlite::Value> = row.unwrap().into();
+                Self::parse_columns(values).unwrap()
+            })
+    }
+
+    /// Stores the region signatures of an image, replacing any previous ones.
+    pub fn insert_regions(
+        &self,
+        id: i64,
+        regions: &[(Region, Signature)],
+    ) -> Result<(), sqlite::Error> {
+        self.transaction(|| {
+            self.remove_regions(id)?;
+            let query = "INSERT INTO regions (id, region, avglf1, avglf2, avglf3, sig)
+                VALUES (:id, :region, :avglf1, :avglf2, :avglf3, :sig)";
+            for (region, sig) in regions {
+                let region = Region::ALL.iter().position(|r| r == region).unwrap();
+                let sig_bytes: Vec<u8> = sig.sig.iter().flat_map(|i| i.to_le_bytes()).collect();
+                let mut statement = self.connection.prepare(query)?;
+                statement.bind::<&[(_, sqlite::Value)]>(
+                    &[
+                        (":id", id.into()),
+                        (":region", (region as i64).into()),
+                        (":avglf1", sig.avgl.0.into()),
+                        (":avglf2", sig.avgl.1.into()),
+                        (":avglf3", sig.avgl.2.into()),
+                        (":sig", sig_bytes.into()),
+                    ][..],
+                )?;
+                if let Some(Err(error)) = statement.into_iter().next() {
+                    return Err(error);
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Deletes the region signatures of an image, returning the first one as
+    /// loaded by [`SqlDB::load_regions`].
+    pub fn delete_regions(&mut self, id: i64) -> Result<Option<ImageData>, sqlite::Error> {
+        self.remove_regions(id)
+    }
+
+    fn remove_regions(&self, id: i64) -> Result<Option<ImageData>, sqlite::Error> {
+        if !self.regions {
+            return Ok(None);
+        }
+        let query = "DELETE FROM regions WHERE id = ?
+            RETURNING id, avglf1, avglf2, avglf3, sig, region";
+        let mut statement = self.connection.prepare(query)?;
+        statement.bind((1, id))?;
+        let mut first: Option<(i64, ImageData)> = None;
+        for row in statement.into_iter() {
+            let row = row?;
+            let region = row.read::<i64, _>("region");
+            if first.as_ref().is_some_and(|(first, _)| *first < region) {
+                continue;
+            }
+            let values: Vec<sqlite::Value> = row.into();
+            first = Some((region, Self::parse_columns(values).unwrap()));
+        }
+        Ok(first.map(|(_, image)| image))
+    }
+
+    /// Inserts or replaces many images in one transaction, returning the first
+    /// frames of the images that were replaced.
+    pub fn insert_many(&mut self, images: &[ImageData]) -> Result<Vec<ImageData>, sqlite::Error> {
+        self.transaction(|| {
+            let mut replaced = Vec::new();
+            for image in images {
+                replaced.extend(self.remove(image.id)?);
+                let sig = Signature {
+                    avgl: image.avgl,
+                    sig: image.sig.clone(),
+                };
+                self.insert(image.id, &sig)?;
+                if !image.metadata.is_empty() {
+                    self.set_metadata(image.id, &image.metadata)?;
+                }
+            }
+            Ok(replaced)
+        })
+    }
+
+    fn transaction<T>(
+        &self,
+        f: impl FnOnce() -> Result<T, sqlite::Error>,
+    ) -> Result<T, sqlite::Error> {
+        self.connection.execute("BEGIN")?;
+        match f() {
+            Ok(value) => {
+                self.connection.execute("COMMIT")?;
+                Ok(value)
+            }
+            Err(error) => {
+                self.connection.execute("ROLLBACK")?;
+                Err(error)
+            }
+        }
+    }
+
+    /// Deletes an image along with its extra frames and metadata, returning its first frame.
     pub fn delete(&mut self, id: i64) -> Result<Option<ImageData>, sqlite::Error> {
+        self.remove(id)
+    }
+
+    fn remove(&self, id: i64) -> Result<Option<ImageData>, sqlite::Error> {
+        let tables = [("frames", self.frames), ("metadata", self.metadata)];
+        for (table, _) in tables.into_iter().filter(|&(_, exists)| exists) {
+            let query = format!("DELETE FROM {table} WHERE id = ?");
+            let mut statement = self.connection.prepare(query)?;
+            statement.bind((1, id))?;
+            if let Some(Err(error)) = statement.into_iter().next() {
+                return Err(error);
+            }
+        }
         let query = match self.schema {
             SqlSchema::V1 => "DELETE FROM images WHERE post_id = ? RETURNING *",
             SqlSchema::V2 => "DELETE FROM images WHERE id = ? RETURNING *",
@@ -134,16 +461,23 @@ impl SqlDB {
         Ok(Some(image))
     }
 
-    fn parse(&self, values: Vec<sqlite::Value>) -> Result<ImageData, ()> {
-        use sqlite::Value::*;
+    fn parse(&self, mut values: Vec<sqlite::Value>) -> Result<ImageData, ()> {
         if values.len() < 5 {
             return Err(());
         }
-        let mut iter = values.into_iter();
         if matches!(self.schema, SqlSchema::V1) {
             // Skip unused ID
-            iter.next();
+            values.remove(0);
         }
+        Self::parse_columns(values)
+    }
+
+    fn parse_columns(values: Vec<sqlite::Value>) -> Result<ImageData, ()> {
+        use sqlite::Value::*;
+        if values.len() < 5 {
+            return Err(());
+        }
+        let mut iter = values.into_iter();
         let slice = [0u32; 5].map(|_| iter.next().unwrap());
         match slice {
             [Integer(id), Float(avglf1), Float(avglf2), Float(avglf3), Binary(sig_bytes)] => {
@@ -161,9 +495,28 @@ impl SqlDB {
                     id,
                     avgl: (avglf1, avglf2, avglf3),
                     sig,
+                    metadata: Metadata::default(),
                 })
             }
             _ => Err(()),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::signature;
+
+    #[test]
+    fn recorded_resampling() {
+        let sql_db = SqlDB::new(sqlite::open(":memory:").unwrap());
+        assert!(sql_db.is_empty().unwrap());
+        assert_eq!(sql_db.resampling().unwrap(), None);
+        sql_db.set_resampling(Resampling::Box).unwrap();
+        assert_eq!(sql_db.resampling().unwrap().as_deref(), Some("box"));
+
+        sql_db.insert(1, &signature(1)).unwrap();
+        assert!(!sql_db.is_empty().unwrap());
+    }
+}
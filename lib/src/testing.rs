@@ -0,0 +1,43 @@
+//! Hand-made signatures and images for tests.
+
+use crate::{ImageData, Metadata, Signature};
+
+/// A signature with the coefficients `first..first + 40` in every channel.
+///
+/// Two such signatures share more coefficients, and score higher against each
+/// other, the closer their `first` are. From 40 apart they share none.
+pub(crate) fn signature(first: i16) -> Signature {
+    Signature {
+        avgl: (0.5, 0., 0.),
+        sig: (0..3).flat_map(|_| first..first + 40).collect(),
+    }
+}
+
+/// An image with the [`signature`] starting at `first`.
+pub(crate) fn image(id: i64, first: i16) -> ImageData {
+    let sig = signature(first);
+    ImageData {
+        id,
+        avgl: sig.avgl,
+        sig: sig.sig,
+        metadata: Metadata::default(),
+    }
+}
+
+/// A busy image, different for every `seed`.
+pub(crate) fn pattern(width: u32, height: u32, seed: u32) -> image::DynamicImage {
+    let img = image::RgbImage::from_fn(width, height, |x, y| {
+        let v = (x * 31 + y * 17 + seed * 101) ^ (x * y * (seed + 3));
+        image::Rgb([
+            v as u8,
+            (v >> 3) as u8 ^ x as u8,
+            (v >> 6) as u8 ^ (y * 3) as u8,
+        ])
+    });
+    image::DynamicImage::ImageRgb8(img)
+}
+
+/// The ids of query results, best first.
+pub(crate) fn ids(result: Vec<(f32, i64)>) -> Vec<i64> {
+    result.into_iter().map(|(_, id)| id).collect()
+}
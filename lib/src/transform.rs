@@ -0,0 +1,154 @@
+use std::{fmt::Display, str::FromStr};
+
+use crate::haar::Signature;
+
+const NUM_PIXELS: u16 = 128;
+
+/// A flip or rotation of an image, applied directly to its [`Signature`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Transform {
+    #[default]
+    None,
+    /// Mirrored left to right.
+    FlipHorizontal,
+    /// Mirrored top to bottom.
+    FlipVertical,
+    /// Rotated clockwise.
+    Rotate90,
+    Rotate180,
+    Rotate270,
+}
+
+impl Transform {
+    pub const ALL: [Self; 6] = [
+        Self::None,
+        Self::FlipHorizontal,
+        Self::FlipVertical,
+        Self::Rotate90,
+        Self::Rotate180,
+        Self::Rotate270,
+    ];
+
+    /// Whether this swaps rows and columns first, then flips them.
+    fn parts(self) -> (bool, bool, bool) {
+        match self {
+            Self::None => (false, false, false),
+            Self::FlipHorizontal => (false, true, false),
+            Self::FlipVertical => (false, false, true),
+            Self::Rotate90 => (true, true, false),
+            Self::Rotate180 => (false, true, true),
+            Self::Rotate270 => (true, false, true),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::FlipHorizontal => "flip_horizontal",
+            Self::FlipVertical => "flip_vertical",
+            Self::Rotate90 => "rotate90",
+            Self::Rotate180 => "rotate180",
+            Self::Rotate270 => "rotate270",
+        }
+    }
+}
+
+impl Display for Transform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Transform {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|transform| transform.name() == s)
+            .ok_or(())
+    }
+}
+
+/// Mirrors a 1D Haar coefficient index, returning it with whether its sign flips.
+///
+/// Mirroring keeps the average and reverses the order of the details within each
+/// level, which also negates them.
+fn flip(index: u16) -> (u16, bool) {
+    if index == 0 {
+        return (0, false);
+    }
+    let level = 1 << index.ilog2();
+    (3 * level - 1 - index, true)
+}
+
+impl Signature {
+    /// The signature of this image after `transform`, derived from the coefficients
+    /// alone. Averages are unchanged, and the result matches hashing the transformed
+    /// image up to rounding.
+    pub fn transformed(&self, transform: Transform) -> Signature {
+        let (transpose, flip_columns, flip_rows) = transform.parts();
+        let mut sig: Vec<i16> = self
+            .sig
+            .iter()
+            .map(|&coef| {
+                let index = coef.unsigned_abs();
+                let (mut row, mut column) = (index / NUM_PIXELS, index % NUM_PIXELS);
+                if transpose {
+                    (row, column) = (column, row);
+                }
+                let mut negative = coef < 0;
+                if flip_columns {
+                    let (flipped, negate) = flip(column);
+                    column = flipped;
+                    negative ^= negate;
+                }
+                if flip_rows {
+                    let (flipped, negate) = flip(row);
+                    row = flipped;
+                    negative ^= negate;
+                }
+                let coef = (row * NUM_PIXELS + column) as i16;
+                if negative {
+                    -coef
+                } else {
+                    coef
+                }
+            })
+            .collect();
+        for coefs in sig.chunks_mut(40) {
+            coefs.sort_unstable();
+        }
+        Signature {
+            avgl: self.avgl,
+            sig,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing::pattern, Signature};
+
+    #[test]
+    fn transforms() {
+        let img = pattern(128, 128, 2);
+        let sig = Signature::from_image(&img);
+        let transformed = [
+            (Transform::None, img.clone()),
+            (Transform::FlipHorizontal, img.fliph()),
+            (Transform::FlipVertical, img.flipv()),
+            (Transform::Rotate90, img.rotate90()),
+            (Transform::Rotate180, img.rotate180()),
+            (Transform::Rotate270, img.rotate270()),
+        ];
+        for (transform, img) in transformed {
+            let expected = Signature::from_image(&img);
+            let result = sig.transformed(transform);
+            assert_eq!(result.sig, expected.sig, "{transform}");
+            assert!((result.avgl.0 - expected.avgl.0).abs() < 1e-9);
+            assert_eq!(transform.name().parse(), Ok(transform));
+        }
+    }
+}
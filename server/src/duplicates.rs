@@ -0,0 +1,65 @@
+use std::io::{self, Write};
+
+use clap::ValueEnum;
+use iqdb_rs::Duplicate;
+use serde::Serialize;
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum OutputFormat {
+    Csv,
+    Json,
+}
+
+#[derive(Serialize)]
+pub struct DuplicateResponse {
+    pub a: i64,
+    pub b: i64,
+    pub score: f32,
+}
+
+impl From<&Duplicate> for DuplicateResponse {
+    fn from(duplicate: &Duplicate) -> Self {
+        Self {
+            a: duplicate.a,
+            b: duplicate.b,
+            score: duplicate.score,
+        }
+    }
+}
+
+/// Writes duplicate pairs, or the clusters they form, to `out`.
+///
+/// Pairs are written as `a,b,score` rows, clusters as `cluster,post_id` rows.
+pub fn write(
+    mut out: impl Write,
+    duplicates: &[Duplicate],
+    clusters: bool,
+    format: OutputFormat,
+) -> io::Result<()> {
+    match (format, clusters) {
+        (OutputFormat::Csv, false) => {
+            writeln!(out, "a,b,score")?;
+            for duplicate in duplicates {
+                writeln!(out, "{},{},{}", duplicate.a, duplicate.b, duplicate.score)?;
+            }
+        }
+        (OutputFormat::Csv, true) => {
+            writeln!(out, "cluster,post_id")?;
+            for (i, cluster) in iqdb_rs::clusters(duplicates).iter().enumerate() {
+                for id in cluster {
+                    writeln!(out, "{i},{id}")?;
+                }
+            }
+        }
+        (OutputFormat::Json, false) => {
+            let pairs: Vec<DuplicateResponse> = duplicates.iter().map(Into::into).collect();
+            serde_json::to_writer(&mut out, &pairs)?;
+            writeln!(out)?;
+        }
+        (OutputFormat::Json, true) => {
+            serde_json::to_writer(&mut out, &iqdb_rs::clusters(duplicates))?;
+            writeln!(out)?;
+        }
+    }
+    out.flush()
+}
@@ -1,21 +1,43 @@
-use std::sync::Arc;
+use std::{
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
 
 use axum::{
+    extract::DefaultBodyLimit,
     routing::{get, post},
     Extension, Router,
 };
-use clap::Parser;
-use iqdb_rs::{SqlDB, DB};
+use clap::{Parser, Subcommand};
+use iqdb_rs::{Resampling, SqlDB, DB};
 use tokio::{
     signal,
     sync::{Mutex, RwLock},
 };
 
+mod duplicates;
 mod response;
 pub use response::{ApiError, ApiResponse};
 mod routes;
 pub mod utils;
 
+/// The largest request body accepted by the batch and bulk endpoints.
+const BULK_BODY_LIMIT: usize = 1 << 30;
+
+#[derive(Clone, Copy)]
+pub struct Config {
+    pub compact_threshold: f64,
+    pub compact_interval: u64,
+    pub resampling: Resampling,
+}
+
+/// The index of image regions, if enabled. Each region is stored as a frame of its image.
+#[derive(Clone)]
+pub struct Regions(pub Option<Arc<RwLock<DB>>>);
+
 #[derive(Parser)]
 #[clap(disable_help_flag = true)]
 struct Args {
@@ -27,38 +49,253 @@ struct Args {
     port: u16,
     /// The path to the sqlite db
     #[arg(short = 'd', long = "database", default_value = "iqdb.sqlite")]
-    db_path: std::path::PathBuf,
+    db_path: PathBuf,
+    /// The path to a binary snapshot of the index, used when newer than the sqlite db
+    #[arg(short = 's', long = "snapshot")]
+    snapshot_path: Option<PathBuf>,
+    /// Compact the index once this fraction of its slots belongs to deleted images
+    #[arg(long = "compact-threshold", default_value_t = 0.25)]
+    compact_threshold: f64,
+    /// How often to check the compaction threshold, in seconds
+    #[arg(long = "compact-interval", default_value_t = 60)]
+    compact_interval: u64,
+    /// Serve queries from a memory-mapped snapshot and reject all changes
+    #[arg(long = "read-only", requires = "snapshot_path")]
+    read_only: bool,
+    /// Check the whole snapshot before serving it read-only, which reads all of it
+    #[arg(long = "verify-snapshot", requires = "read_only")]
+    verify_snapshot: bool,
+    /// Also index regions of uploaded images, so that crops of them can be found
+    #[arg(long = "regions")]
+    regions: bool,
+    /// How images are scaled before hashing: `libgd` matches the original iqdb, `box` is
+    /// faster and scores within 1% of it, and `libgd-legacy` matches translucent images
+    /// hashed by versions before alpha handling
+    #[arg(
+        long = "resampler",
+        value_name = "RESAMPLER",
+        default_value = "libgd",
+        value_parser = parse_resampling
+    )]
+    resampling: Resampling,
+    /// Refuse to start when the database was hashed with another resampler, instead
+    /// of warning
+    #[arg(long = "strict-resampler")]
+    strict_resampling: bool,
 
     /// Print help
     #[clap(long, action = clap::ArgAction::HelpLong)]
     help: Option<bool>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+fn parse_resampling(s: &str) -> Result<Resampling, String> {
+    s.parse()
+        .map_err(|_| format!("expected `libgd`, `libgd-legacy` or `box`, got `{s}`"))
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Write every pair of near-duplicate images to a file instead of serving
+    Duplicates {
+        /// The lowest score at which two images count as duplicates
+        #[arg(short = 't', long = "threshold", default_value_t = 90.)]
+        threshold: f32,
+        /// Write clusters of linked images instead of pairs
+        #[arg(long = "clusters")]
+        clusters: bool,
+        /// The output format
+        #[arg(short = 'f', long = "format", value_enum, default_value = "csv")]
+        format: duplicates::OutputFormat,
+        /// The file to write to
+        #[arg(short = 'o', long = "output")]
+        output: PathBuf,
+    },
 }
 
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
-    let sql_connection = sqlite::open(args.db_path).unwrap();
-    let sql_db = SqlDB::new(sql_connection);
-    let db = DB::new(sql_db.load());
+    let sql_db = if args.read_only {
+        let flags = sqlite::OpenFlags::new().with_read_only();
+        SqlDB::new(sqlite::Connection::open_with_flags(&args.db_path, flags).unwrap())
+    } else {
+        SqlDB::new(sqlite::open(&args.db_path).unwrap())
+    };
+    check_resampling(
+        &sql_db,
+        args.resampling,
+        args.strict_resampling,
+        args.read_only,
+    );
+    let db = match (&args.snapshot_path, args.read_only) {
+        (Some(snapshot_path), true) if args.verify_snapshot => {
+            DB::open_snapshot_verified(snapshot_path).unwrap()
+        }
+        (Some(snapshot_path), true) => DB::open_snapshot(snapshot_path).unwrap(),
+        (snapshot_path, _) => load_db(&sql_db, &args.db_path, snapshot_path.as_deref()),
+    };
+    if let Some(command) = args.command {
+        run_command(command, &db);
+        return;
+    }
+
+    let regions = Regions(
+        args.regions
+            .then(|| Arc::new(RwLock::new(DB::new(sql_db.load_regions())))),
+    );
 
     let db = Arc::new(RwLock::new(db));
     let sql_db = Arc::new(Mutex::new(sql_db));
+    let config = Config {
+        compact_threshold: args.compact_threshold,
+        compact_interval: args.compact_interval,
+        resampling: args.resampling,
+    };
+    let duplicates = routes::admin::DuplicatesState::default();
+    if !args.read_only {
+        let compaction = routes::admin::compact_periodically(
+            db.clone(),
+            regions.clone(),
+            duplicates.clone(),
+            config,
+        );
+        tokio::spawn(compaction);
+    }
 
     let app = Router::new()
         .route("/query", get(routes::query::get).post(routes::query::get))
+        .route(
+            "/query/batch",
+            post(routes::query::batch).layer(DefaultBodyLimit::max(BULK_BODY_LIMIT)),
+        )
+        .route(
+            "/images/bulk",
+            post(routes::images::bulk).layer(DefaultBodyLimit::max(BULK_BODY_LIMIT)),
+        )
         .route(
             "/images/:id",
-            post(routes::images::post).delete(routes::images::delete),
+            get(routes::images::get)
+                .head(routes::images::head)
+                .post(routes::images::post)
+                .delete(routes::images::delete),
         )
         .route("/status", get(routes::status::get))
-        .layer(Extension(db))
-        .layer(Extension(sql_db));
+        .route("/admin/compact", post(routes::admin::compact))
+        .route(
+            "/admin/duplicates",
+            get(routes::admin::get_duplicates).post(routes::admin::start_duplicates),
+        )
+        .layer(Extension(config))
+        .layer(Extension(db.clone()))
+        .layer(Extension(sql_db))
+        .layer(Extension(regions))
+        .layer(Extension(duplicates));
     let addr = format!("{}:{}", args.host, args.port);
     let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
     axum::serve(listener, app)
         .with_graceful_shutdown(shutdown_signal())
         .await
         .unwrap();
+
+    if let (Some(snapshot_path), false) = (&args.snapshot_path, args.read_only) {
+        let db = db.read().await;
+        save_snapshot(&db, snapshot_path);
+    }
+}
+
+fn run_command(command: Command, db: &DB) {
+    match command {
+        Command::Duplicates {
+            threshold,
+            clusters,
+            format,
+            output,
+        } => {
+            let step = (db.slot_count() / 20).max(1);
+            let found = db.find_duplicates_with_progress(threshold, |done, total| {
+                if done % step == 0 || done == total {
+                    println!("Checked: {done}/{total}");
+                }
+            });
+            println!("Duplicates: {}", found.len());
+            let file = File::create(&output).unwrap();
+            duplicates::write(BufWriter::new(file), &found, clusters, format).unwrap();
+        }
+    }
+}
+
+/// Warns about signatures computed with another resampler than `resampling`, or
+/// refuses to serve them if `strict`, and records it for new databases.
+///
+/// The resamplers hash opaque images within 1% of each other, so mixing them only
+/// costs a little precision, but a database stays consistent only with the one it
+/// was hashed with.
+fn check_resampling(sql_db: &SqlDB, resampling: Resampling, strict: bool, read_only: bool) {
+    let recorded = sql_db.resampling().unwrap();
+    let expected = match &recorded {
+        Some(recorded) => recorded.as_str(),
+        // Databases that predate the record were hashed by the original iqdb or with
+        // libgd, which earlier versions of this server weighted like `libgd-legacy`.
+        None if !sql_db.is_empty().unwrap() => match resampling {
+            Resampling::LibgdLegacy => resampling.name(),
+            _ => Resampling::Libgd.name(),
+        },
+        None => resampling.name(),
+    };
+    if expected != resampling.name() {
+        let message =
+            format!("The database was hashed with `--resampler {expected}`, not `{resampling}`");
+        if strict {
+            eprintln!("{message}");
+            std::process::exit(1);
+        }
+        println!("Warning: {message}, so new signatures score slightly differently");
+    }
+    if recorded.is_none() && !read_only {
+        sql_db.set_resampling(resampling).unwrap();
+    }
+}
+
+fn load_db(sql_db: &SqlDB, db_path: &Path, snapshot_path: Option<&Path>) -> DB {
+    let Some(snapshot_path) = snapshot_path else {
+        return DB::new(sql_db.load());
+    };
+    if snapshot_is_fresh(db_path, snapshot_path) {
+        match DB::load_snapshot(snapshot_path) {
+            Ok(db) => return db,
+            Err(error) => println!("Failed to load snapshot: {error}"),
+        }
+    } else {
+        println!("Snapshot is missing or older than the database");
+    }
+    let db = DB::new(sql_db.load());
+    save_snapshot(&db, snapshot_path);
+    db
+}
+
+fn save_snapshot(db: &DB, snapshot_path: &Path) {
+    match db.save_snapshot(snapshot_path) {
+        Ok(()) => println!("Saved snapshot: {}", snapshot_path.display()),
+        Err(error) => println!("Failed to save snapshot: {error}"),
+    }
+}
+
+fn snapshot_is_fresh(db_path: &Path, snapshot_path: &Path) -> bool {
+    let modified = |path: &Path| path.metadata().and_then(|m| m.modified()).ok();
+    let Some(snapshot_modified) = modified(snapshot_path) else {
+        return false;
+    };
+    let mut wal_path = db_path.as_os_str().to_owned();
+    wal_path.push("-wal");
+    let db_modified = [db_path, Path::new(&wal_path)]
+        .into_iter()
+        .filter_map(modified)
+        .max()
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+    snapshot_modified > db_modified
 }
 
 async fn shutdown_signal() {
@@ -1,5 +1,6 @@
 use axum::{http::StatusCode, Json};
-use serde::Serialize;
+use iqdb_rs::Signature;
+use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "snake_case")]
@@ -8,10 +9,35 @@ pub enum ApiError {
     MissingFileOrHash,
 
     InvalidFile,
-    InvalidHash,
-    InvalidImage,
+    InvalidHash {
+        message: String,
+    },
+    InvalidSignature,
+    InvalidBody {
+        message: String,
+    },
+    InvalidImage {
+        message: String,
+    },
+    InvalidAlpha,
+    InvalidFrames,
+    InvalidMetadata,
+    InvalidBatch,
+    InvalidWeights,
+    InvalidProfile {
+        message: String,
+    },
+    InvalidMode {
+        message: String,
+    },
+    InvalidTransform,
 
     NotFound,
+    DuplicateId,
+    ReadOnly,
+    RegionsDisabled,
+    FramesUnsupported,
+    JobRunning,
 
     Sqlite {
         code: Option<isize>,
@@ -19,6 +45,23 @@ pub enum ApiError {
     },
 }
 
+impl From<iqdb_rs::ReadOnlyError> for ApiError {
+    fn from(_: iqdb_rs::ReadOnlyError) -> Self {
+        Self::ReadOnly
+    }
+}
+
+impl From<iqdb_rs::SignatureError> for ApiError {
+    fn from(error: iqdb_rs::SignatureError) -> Self {
+        use iqdb_rs::SignatureError::*;
+        let message = error.to_string();
+        match error {
+            BadHashLength { .. } | BadHex | InvalidCoefficients => Self::InvalidHash { message },
+            _ => Self::InvalidImage { message },
+        }
+    }
+}
+
 impl From<sqlite::Error> for ApiError {
     fn from(value: sqlite::Error) -> Self {
         Self::Sqlite {
@@ -45,8 +88,43 @@ impl<T, E> ApiResponse<T, E> {
     }
 }
 
-#[derive(Serialize)]
+/// The part of an image that was hashed, after trimming its borders.
+#[derive(Clone, Copy, Serialize)]
+pub struct BoundsResponse {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl From<(u32, u32, u32, u32)> for BoundsResponse {
+    fn from((x, y, width, height): (u32, u32, u32, u32)) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct SignatureResponse {
     pub avglf: (f64, f64, f64),
     pub sig: Vec<i16>,
 }
+
+impl TryFrom<SignatureResponse> for Signature {
+    type Error = ApiError;
+
+    fn try_from(value: SignatureResponse) -> Result<Self, Self::Error> {
+        let sig = Signature {
+            avgl: value.avglf,
+            sig: value.sig,
+        };
+        if !sig.is_valid() {
+            return Err(ApiError::InvalidSignature);
+        }
+        Ok(sig)
+    }
+}
@@ -0,0 +1,266 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use axum::{extract::Query, http::StatusCode, Extension, Json};
+use iqdb_rs::{Duplicate, DuplicateSearch, Filter, ReadOnlyError, DB};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex, RwLock};
+
+use crate::{
+    duplicates::DuplicateResponse, utils::query_options, ApiError, ApiResponse, Config, Regions,
+};
+
+#[derive(Serialize)]
+pub struct CompactResponse {
+    pub reclaimed: usize,
+    pub images: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub regions_reclaimed: Option<usize>,
+}
+
+/// How many slots a duplicate search scans between taking and releasing the read lock.
+const DUPLICATES_BATCH: usize = 1024;
+
+/// Compacts the index, and the region index if enabled.
+pub async fn compact(
+    Extension(db): Extension<Arc<RwLock<DB>>>,
+    Extension(regions): Extension<Regions>,
+    Extension(job): Extension<DuplicatesState>,
+) -> (StatusCode, Json<ApiResponse<CompactResponse>>) {
+    // Compacting moves images to other slots, which a running search would skip.
+    if is_running(&job).await {
+        return ApiResponse::err(ApiError::JobRunning, StatusCode::CONFLICT);
+    }
+    let reclaimed = match compact_blocking(db.clone()).await {
+        Ok(reclaimed) => reclaimed,
+        Err(e) => return ApiResponse::err(e.into(), StatusCode::FORBIDDEN),
+    };
+    println!("Compacted: {reclaimed}");
+    let regions_reclaimed = match regions.0 {
+        Some(regions) => match compact_blocking(regions).await {
+            Ok(reclaimed) => Some(reclaimed),
+            Err(e) => return ApiResponse::err(e.into(), StatusCode::FORBIDDEN),
+        },
+        None => None,
+    };
+
+    let response = CompactResponse {
+        reclaimed,
+        images: db.read().await.image_count(),
+        regions_reclaimed,
+    };
+    ApiResponse::ok(response)
+}
+
+/// Runs [`DB::compact`] on the blocking pool, so the write lock does not stall a
+/// runtime worker while the chunks are rebuilt.
+async fn compact_blocking(db: Arc<RwLock<DB>>) -> Result<usize, ReadOnlyError> {
+    tokio::task::spawn_blocking(move || db.blocking_write().compact())
+        .await
+        .unwrap()
+}
+
+/// The latest duplicate search, kept until the next one starts.
+pub struct DuplicatesJob {
+    threshold: f32,
+    done: Arc<AtomicUsize>,
+    total: Arc<AtomicUsize>,
+    result: Option<Vec<Duplicate>>,
+}
+
+pub type DuplicatesState = Arc<Mutex<Option<DuplicatesJob>>>;
+
+fn duplicates_default_threshold() -> f32 {
+    90.
+}
+
+#[derive(Deserialize)]
+pub struct DuplicatesQuery {
+    #[serde(default = "duplicates_default_threshold")]
+    pub threshold: f32,
+    pub profile: Option<String>,
+    pub mode: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct DuplicatesResponse {
+    pub running: bool,
+    pub threshold: f32,
+    pub done: usize,
+    pub total: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pairs: Option<Vec<DuplicateResponse>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clusters: Option<Vec<Vec<i64>>>,
+}
+
+impl DuplicatesJob {
+    fn response(&self) -> DuplicatesResponse {
+        let result = self.result.as_deref();
+        DuplicatesResponse {
+            running: result.is_none(),
+            threshold: self.threshold,
+            done: self.done.load(Ordering::Relaxed),
+            total: self.total.load(Ordering::Relaxed),
+            pairs: result.map(|result| result.iter().map(Into::into).collect()),
+            clusters: result.map(iqdb_rs::clusters),
+        }
+    }
+}
+
+async fn is_running(job: &DuplicatesState) -> bool {
+    job.lock()
+        .await
+        .as_ref()
+        .is_some_and(|job| job.result.is_none())
+}
+
+/// Starts a search for every pair of near-duplicate images in the background.
+///
+/// The search takes a read lock on the index for every [`DUPLICATES_BATCH`] slots,
+/// so changes only wait for the current batch. Images added meanwhile are scanned too.
+/// Pairs are scored with the `profile` and `mode` given, as for queries.
+pub async fn start_duplicates(
+    Extension(db): Extension<Arc<RwLock<DB>>>,
+    Extension(job): Extension<DuplicatesState>,
+    Query(query): Query<DuplicatesQuery>,
+) -> (StatusCode, Json<ApiResponse<DuplicatesResponse>>) {
+    let options = match query_options(
+        query.profile.as_deref(),
+        None,
+        query.mode.as_deref(),
+        Filter::default(),
+        None,
+    ) {
+        Ok(options) => options,
+        Err(error) => return ApiResponse::err(error, StatusCode::BAD_REQUEST),
+    };
+    let mut job_guard = job.lock().await;
+    if job_guard.as_ref().is_some_and(|job| job.result.is_none()) {
+        return ApiResponse::err(ApiError::JobRunning, StatusCode::CONFLICT);
+    }
+    let done = Arc::new(AtomicUsize::new(0));
+    let total = Arc::new(AtomicUsize::new(db.read().await.slot_count()));
+    let new_job = DuplicatesJob {
+        threshold: query.threshold,
+        done: done.clone(),
+        total: total.clone(),
+        result: None,
+    };
+    let response = new_job.response();
+    *job_guard = Some(new_job);
+    drop(job_guard);
+
+    let mut search = DuplicateSearch::with_options(query.threshold, options);
+    tokio::task::spawn_blocking(move || {
+        let progress = |count, slots| {
+            done.fetch_max(count, Ordering::Relaxed);
+            total.store(slots, Ordering::Relaxed);
+        };
+        while scan_batch(&db, &mut search, &progress) {}
+        let result = search.finish();
+        println!("Duplicates: {}", result.len());
+        if let Some(job) = job.blocking_lock().as_mut() {
+            job.result = Some(result);
+        }
+    });
+    ApiResponse::ok(response)
+}
+
+/// Scans the next [`DUPLICATES_BATCH`] slots, holding the read lock only meanwhile.
+fn scan_batch(
+    db: &RwLock<DB>,
+    search: &mut DuplicateSearch,
+    progress: &(impl Fn(usize, usize) + Sync),
+) -> bool {
+    search.step(&db.blocking_read(), DUPLICATES_BATCH, progress)
+}
+
+/// Reports the progress of the latest duplicate search, and its results once done.
+pub async fn get_duplicates(
+    Extension(job): Extension<DuplicatesState>,
+) -> (StatusCode, Json<ApiResponse<DuplicatesResponse>>) {
+    match job.lock().await.as_ref() {
+        Some(job) => ApiResponse::ok(job.response()),
+        None => ApiResponse::err(ApiError::NotFound, StatusCode::NOT_FOUND),
+    }
+}
+
+/// Compacts the index and the region index whenever deleted images pass the
+/// configured threshold, checking every `compact_interval` seconds unless a
+/// duplicate search is running.
+pub async fn compact_periodically(
+    db: Arc<RwLock<DB>>,
+    regions: Regions,
+    job: DuplicatesState,
+    config: Config,
+) {
+    let period = Duration::from_secs(config.compact_interval.max(1));
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    loop {
+        interval.tick().await;
+        if is_running(&job).await {
+            continue;
+        }
+        for db in std::iter::once(&db).chain(&regions.0) {
+            compact_if_needed(db.clone(), config.compact_threshold).await;
+        }
+    }
+}
+
+async fn compact_if_needed(db: Arc<RwLock<DB>>, threshold: f64) {
+    // Checking under a read lock leaves queries running when there is nothing to do.
+    if db.read().await.deleted_ratio() <= threshold {
+        return;
+    }
+    match compact_blocking(db).await {
+        Ok(reclaimed) => println!("Compacted: {reclaimed}"),
+        Err(error) => println!("Failed to compact: {error}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iqdb_rs::{ImageData, Metadata};
+
+    use super::*;
+
+    /// An image with its own set of coefficients, so that no two of the first 2000
+    /// images match.
+    fn image(id: i64) -> ImageData {
+        let sig = (0..3)
+            .flat_map(|c| (0..40).map(move |k| ((id * 7 + k * 389 + c * 13) % 16000 + 1) as i16))
+            .collect();
+        ImageData {
+            id,
+            avgl: (0.5, 0., 0.),
+            sig,
+            metadata: Metadata::default(),
+        }
+    }
+
+    #[test]
+    fn insert_during_duplicates() {
+        let db = RwLock::new(DB::new((0..2 * DUPLICATES_BATCH as i64).map(image)));
+        let mut search = DuplicateSearch::new(90.);
+        assert!(scan_batch(&db, &mut search, &|_, _| ()));
+        assert_eq!(search.done(), DUPLICATES_BATCH);
+
+        // The lock is free between batches, so writes only wait for the batch being
+        // scanned. A copy of the first image is found even though it was added late.
+        let copy = ImageData {
+            id: 5000,
+            ..image(0)
+        };
+        db.try_write().unwrap().insert(copy).unwrap();
+        while scan_batch(&db, &mut search, &|_, _| ()) {}
+        assert_eq!(search.done(), 2 * DUPLICATES_BATCH + 1);
+        let pairs: Vec<_> = search.finish().iter().map(|d| (d.a, d.b)).collect();
+        assert_eq!(pairs, [(0, 5000)]);
+    }
+}
@@ -1,15 +1,66 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
 use axum::{
-    extract::{Multipart, Path},
-    http::StatusCode,
+    extract::{FromRequest, Multipart, Path, Query, Request},
+    http::{header::CONTENT_TYPE, StatusCode},
     Extension, Json,
 };
-use iqdb_rs::{ImageData, DB};
-use serde::Serialize;
+use image::DynamicImage;
+use iqdb_rs::{FrameSelection, ImageData, Metadata, Region, Signature, SignatureOptions, DB};
+use serde::{Deserialize, Serialize};
 use tokio::sync::{Mutex, RwLock};
 
-use crate::{response::SignatureResponse, utils::get_signature, ApiError, ApiResponse, SqlDB};
+use crate::{
+    response::{BoundsResponse, SignatureResponse},
+    utils::{
+        compute_signatures, decode_file, form_file, frame_selection, frame_signatures,
+        get_signatures, parse_ratings, parse_tags, signature_options, SignatureInput,
+    },
+    ApiError, ApiResponse, Config, Regions, SqlDB,
+};
+
+#[derive(Deserialize)]
+pub struct PostImageQuery {
+    #[serde(alias = "h")]
+    pub hash: Option<String>,
+    pub alpha: Option<String>,
+    pub trim: Option<u8>,
+    pub frames: Option<String>,
+    pub tags: Option<String>,
+    pub rating: Option<String>,
+    pub timestamp: Option<i64>,
+}
+
+impl PostImageQuery {
+    fn metadata(&self) -> Result<Metadata, ApiError> {
+        metadata(self.tags.as_deref(), self.rating.as_deref(), self.timestamp)
+    }
+}
+
+fn metadata(
+    tags: Option<&str>,
+    rating: Option<&str>,
+    timestamp: Option<i64>,
+) -> Result<Metadata, ApiError> {
+    let rating = match parse_ratings(rating)?[..] {
+        [] => 0,
+        [rating] => rating,
+        _ => return Err(ApiError::InvalidMetadata),
+    };
+    Ok(Metadata {
+        tags: parse_tags(tags)?,
+        rating,
+        timestamp: timestamp.unwrap_or_default(),
+    })
+}
+
+/// A JSON body for [`post`], holding either a hash or a raw signature.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum PostImageBody {
+    Hash { hash: String },
+    Signature(SignatureResponse),
+}
 
 #[derive(Serialize)]
 pub struct PostImageResponse {
@@ -17,8 +68,56 @@ pub struct PostImageResponse {
     pub id: i64,
     pub hash: String,
     pub signature: SignatureResponse,
+    pub frames: usize,
+    /// The part of the first frame that was hashed, when trimming borders of a file.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trimmed: Option<BoundsResponse>,
+}
+
+#[derive(Deserialize)]
+pub struct BulkQuery {
+    pub alpha: Option<String>,
+    pub trim: Option<u8>,
+}
+
+#[derive(Deserialize)]
+pub struct BulkImage {
+    #[serde(rename = "post_id")]
+    pub id: i64,
+    pub hash: String,
+    #[serde(flatten)]
+    pub fields: BulkFields,
+}
+
+/// The fields of a single image in [`bulk`], besides its id and signature.
+#[derive(Default, Deserialize)]
+pub struct BulkFields {
+    pub tags: Option<String>,
+    pub rating: Option<String>,
+    pub timestamp: Option<i64>,
+    /// Only the first frame of each file is hashed, so images asking for frames are
+    /// refused rather than indexed with fewer.
+    pub frames: Option<String>,
+}
+
+impl BulkFields {
+    fn metadata(&self) -> Result<Metadata, ApiError> {
+        if self.frames.is_some() {
+            return Err(ApiError::FramesUnsupported);
+        }
+        metadata(self.tags.as_deref(), self.rating.as_deref(), self.timestamp)
+    }
+}
+
+#[derive(Serialize)]
+pub struct BulkImageResponse {
+    #[serde(rename = "post_id")]
+    pub id: i64,
+    pub hash: String,
 }
 
+pub type BulkResponse = Vec<ApiResponse<BulkImageResponse>>;
+
 #[derive(Serialize)]
 pub struct DeleteImageResponse {
     #[serde(rename = "post_id")]
@@ -26,13 +125,28 @@ pub struct DeleteImageResponse {
 }
 
 pub async fn post(
+    Extension(config): Extension<Config>,
     Extension(sql_db): Extension<Arc<Mutex<SqlDB>>>,
     Extension(db): Extension<Arc<RwLock<DB>>>,
+    Extension(regions): Extension<Regions>,
     Path(id): Path<i64>,
-    form: Multipart,
+    Query(query): Query<PostImageQuery>,
+    request: Request,
 ) -> (StatusCode, Json<ApiResponse<PostImageResponse>>) {
-    let sig = match get_signature(None, Some(form)).await {
-        Ok(sig) => sig,
+    let options = match signature_options(query.alpha.as_deref(), query.trim, config.resampling) {
+        Ok(options) => options,
+        Err(error) => return ApiResponse::err(error, StatusCode::BAD_REQUEST),
+    };
+    let frames = match frame_selection(query.frames.as_deref()) {
+        Ok(frames) => frames,
+        Err(error) => return ApiResponse::err(error, StatusCode::BAD_REQUEST),
+    };
+    let metadata = match query.metadata() {
+        Ok(metadata) => metadata,
+        Err(error) => return ApiResponse::err(error, StatusCode::BAD_REQUEST),
+    };
+    let (sigs, first_frame) = match post_signatures(query.hash, request, &options, frames).await {
+        Ok(result) => result,
         Err(mut error) => {
             if matches!(error, ApiError::MissingFileOrHash) {
                 error = ApiError::MissingFile;
@@ -40,31 +154,52 @@ pub async fn post(
             return ApiResponse::err(error, StatusCode::BAD_REQUEST);
         }
     };
+    let (sigs, trimmed): (Vec<_>, Vec<_>) = sigs.into_iter().unzip();
+    // Regions can only be cut from an uploaded file, not from a hash, and are cut from
+    // the frame that was decoded for hashing.
+    let region_sigs = match (&regions.0, first_frame) {
+        (Some(_), Some(img)) => Some(Signature::from_image_regions(&img, &options)),
+        _ => None,
+    };
 
     let mut db = db.write().await;
+    if db.is_read_only() {
+        return ApiResponse::err(ApiError::ReadOnly, StatusCode::FORBIDDEN);
+    }
 
     if db.contains(id) {
         let mut sql_db = sql_db.lock().await;
-        match sql_db.delete(id) {
+        let result = match sql_db.delete(id) {
             Ok(Some(image)) => db.delete(image),
             Ok(None) => unreachable!(),
             Err(e) => return ApiResponse::err(e.into(), StatusCode::INTERNAL_SERVER_ERROR),
         };
+        if let Err(e) = result {
+            return ApiResponse::err(e.into(), StatusCode::FORBIDDEN);
+        }
     }
 
     {
         let sql_db = sql_db.lock().await;
-        if let Err(error) = sql_db.insert(id, &sig) {
+        if let Err(error) = sql_db.insert_frames(id, &sigs) {
             return ApiResponse::err(error.into(), StatusCode::INTERNAL_SERVER_ERROR);
         };
+        if !metadata.is_empty() {
+            if let Err(error) = sql_db.set_metadata(id, &metadata) {
+                return ApiResponse::err(error.into(), StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        }
     }
 
-    db.insert(ImageData {
-        id,
-        avgl: sig.avgl,
-        sig: sig.sig.clone(),
-    });
+    if let Err(e) = db.insert_frames(id, &sigs, metadata) {
+        return ApiResponse::err(e.into(), StatusCode::FORBIDDEN);
+    }
+    if let Err(error) = update_regions(&regions, &sql_db, id, region_sigs, metadata).await {
+        return ApiResponse::err(error, StatusCode::INTERNAL_SERVER_ERROR);
+    }
 
+    let frames = sigs.len();
+    let sig = sigs.into_iter().next().unwrap();
     let response = PostImageResponse {
         id,
         hash: sig.to_string(),
@@ -72,16 +207,296 @@ pub async fn post(
             avglf: sig.avgl,
             sig: sig.sig,
         },
+        frames,
+        trimmed: trimmed[0].filter(|_| options.trim.is_some()),
     };
     ApiResponse::ok(response)
 }
 
+/// Returns the stored signature of an image.
+pub async fn get(
+    Extension(sql_db): Extension<Arc<Mutex<SqlDB>>>,
+    Path(id): Path<i64>,
+) -> (StatusCode, Json<ApiResponse<PostImageResponse>>) {
+    let sql_db = sql_db.lock().await;
+    let Some(image) = sql_db.get_many([id]).next() else {
+        return ApiResponse::err(ApiError::NotFound, StatusCode::NOT_FOUND);
+    };
+    let frames = match sql_db.frames_of(id) {
+        Ok(frames) => frames,
+        Err(e) => return ApiResponse::err(e.into(), StatusCode::INTERNAL_SERVER_ERROR),
+    };
+    let signature = Signature {
+        avgl: image.avgl,
+        sig: image.sig,
+    };
+    let response = PostImageResponse {
+        id,
+        hash: signature.to_string(),
+        signature: SignatureResponse {
+            avglf: signature.avgl,
+            sig: signature.sig,
+        },
+        frames,
+        trimmed: None,
+    };
+    ApiResponse::ok(response)
+}
+
+/// Checks whether an image is indexed, without touching the sqlite db.
+pub async fn head(Extension(db): Extension<Arc<RwLock<DB>>>, Path(id): Path<i64>) -> StatusCode {
+    if db.read().await.contains(id) {
+        StatusCode::OK
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+/// Replaces the regions of an image in the region index, if it is enabled.
+///
+/// With no new regions, the image is only removed from the region index.
+async fn update_regions(
+    regions: &Regions,
+    sql_db: &Mutex<SqlDB>,
+    id: i64,
+    region_sigs: Option<Vec<(Region, Signature)>>,
+    metadata: Metadata,
+) -> Result<(), ApiError> {
+    let Some(regions) = &regions.0 else {
+        return Ok(());
+    };
+    let mut regions = regions.write().await;
+    let mut sql_db = sql_db.lock().await;
+    if let Some(image) = sql_db.delete_regions(id)? {
+        regions.delete(image)?;
+    }
+    if let Some(region_sigs) = region_sigs {
+        sql_db.insert_regions(id, &region_sigs)?;
+        for (_, sig) in region_sigs {
+            regions.insert_frame(ImageData {
+                id,
+                avgl: sig.avgl,
+                sig: sig.sig,
+                metadata,
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// Takes the signature from the `hash` query parameter, a JSON body or an uploaded file.
+///
+/// Each frame of an uploaded file comes with the part of it that was hashed, and the
+/// first decoded frame is returned as well.
+async fn post_signatures(
+    hash: Option<String>,
+    request: Request,
+    options: &SignatureOptions,
+    frames: FrameSelection,
+) -> Result<
+    (
+        Vec<(Signature, Option<BoundsResponse>)>,
+        Option<DynamicImage>,
+    ),
+    ApiError,
+> {
+    if hash.is_some() {
+        let sigs = get_signatures(hash, None, options, frames).await?;
+        return Ok((sigs.into_iter().map(|sig| (sig, None)).collect(), None));
+    }
+    let content_type = request.headers().get(CONTENT_TYPE);
+    let content_type = content_type.and_then(|value| value.to_str().ok());
+    if content_type.is_some_and(|value| value.starts_with("application/json")) {
+        let Json(body) = Json::<PostImageBody>::from_request(request, &())
+            .await
+            .map_err(|_| ApiError::InvalidSignature)?;
+        let sig = match body {
+            PostImageBody::Hash { hash } => hash.parse()?,
+            PostImageBody::Signature(sig) => sig.try_into()?,
+        };
+        return Ok((vec![(sig, None)], None));
+    }
+    let form = Multipart::from_request(request, &())
+        .await
+        .map_err(|_| ApiError::MissingFile)?;
+    let file = form_file(form).await?;
+    let (decoded, size) = decode_file(&file, frames)?;
+    let sigs = frame_signatures(&decoded, size, options);
+    let sigs = sigs.into_iter().map(|(sig, bounds)| (sig, Some(bounds)));
+    Ok((sigs.collect(), decoded.into_iter().next()))
+}
+
+/// Adds or replaces many images at once.
+///
+/// Takes either a multipart form of `post_id` fields, each followed by optional `tags`,
+/// `rating` and `timestamp` fields and then a `file` or `hash` field, or a JSON array
+/// of `{"post_id", "hash"}` objects with the same optional keys. Images with `frames`
+/// are refused, as only the first frame is hashed. Signatures are computed before any
+/// lock is taken, and results come back in input order.
+pub async fn bulk(
+    Extension(config): Extension<Config>,
+    Extension(sql_db): Extension<Arc<Mutex<SqlDB>>>,
+    Extension(db): Extension<Arc<RwLock<DB>>>,
+    Extension(regions): Extension<Regions>,
+    Query(query): Query<BulkQuery>,
+    request: Request,
+) -> (StatusCode, Json<ApiResponse<BulkResponse>>) {
+    let options = match signature_options(query.alpha.as_deref(), query.trim, config.resampling) {
+        Ok(options) => options,
+        Err(error) => return ApiResponse::err(error, StatusCode::BAD_REQUEST),
+    };
+    if db.read().await.is_read_only() {
+        return ApiResponse::err(ApiError::ReadOnly, StatusCode::FORBIDDEN);
+    }
+    let inputs = match bulk_inputs(request).await {
+        Ok(inputs) => inputs,
+        Err(error) => return ApiResponse::err(error, StatusCode::BAD_REQUEST),
+    };
+
+    let (ids, inputs): (Vec<_>, Vec<_>) = inputs.into_iter().unzip();
+    let (inputs, metadata): (Vec<_>, Vec<_>) = inputs.into_iter().unzip();
+    let signatures = compute_signatures(inputs, &options).await;
+    let mut signatures: Vec<_> = signatures
+        .into_iter()
+        .zip(metadata)
+        .map(|(sig, metadata)| Ok((sig?, metadata?)))
+        .collect();
+    // The last entry for an id wins.
+    let last: HashMap<_, _> = ids.iter().enumerate().map(|(i, &id)| (id, i)).collect();
+    for (i, sig) in signatures.iter_mut().enumerate() {
+        if last[&ids[i]] != i {
+            *sig = Err(ApiError::DuplicateId);
+        }
+    }
+    let images: Vec<_> = ids
+        .iter()
+        .zip(&signatures)
+        .filter_map(|(&id, sig)| {
+            let (sig, metadata) = sig.as_ref().ok()?;
+            Some(ImageData {
+                id,
+                avgl: sig.avgl,
+                sig: sig.sig.clone(),
+                metadata: *metadata,
+            })
+        })
+        .collect();
+
+    let mut db = db.write().await;
+    let replaced = {
+        let mut sql_db = sql_db.lock().await;
+        match sql_db.insert_many(&images) {
+            Ok(replaced) => replaced,
+            Err(e) => return ApiResponse::err(e.into(), StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    };
+    for image in replaced {
+        let id = image.id;
+        if let Err(e) = db.delete(image) {
+            return ApiResponse::err(e.into(), StatusCode::FORBIDDEN);
+        }
+        // Bulk uploads are not cut into regions, so stale ones are only dropped.
+        let metadata = Metadata::default();
+        if let Err(error) = update_regions(&regions, &sql_db, id, None, metadata).await {
+            return ApiResponse::err(error, StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    }
+    if let Err(e) = db.insert_many(images) {
+        return ApiResponse::err(e.into(), StatusCode::FORBIDDEN);
+    }
+
+    let response = ids
+        .into_iter()
+        .zip(signatures)
+        .map(|(id, sig)| match sig {
+            Ok((sig, _)) => ApiResponse::Ok(BulkImageResponse {
+                id,
+                hash: sig.to_string(),
+            }),
+            Err(error) => ApiResponse::Err { error },
+        })
+        .collect();
+    ApiResponse::ok(response)
+}
+
+/// Reads the id, signature input and metadata of every image, where bad metadata only
+/// fails its own image.
+async fn bulk_inputs(
+    request: Request,
+) -> Result<Vec<(i64, (SignatureInput, Result<Metadata, ApiError>))>, ApiError> {
+    let content_type = request.headers().get(CONTENT_TYPE);
+    let content_type = content_type.and_then(|value| value.to_str().ok());
+    if !content_type.is_some_and(|value| value.starts_with("multipart/form-data")) {
+        let Json(images) = Json::<Vec<BulkImage>>::from_request(request, &())
+            .await
+            .map_err(|_| ApiError::InvalidBatch)?;
+        let inputs = images.into_iter();
+        return Ok(inputs
+            .map(|image| {
+                let metadata = image.fields.metadata();
+                (image.id, (SignatureInput::Hash(image.hash), metadata))
+            })
+            .collect());
+    }
+
+    let mut form = Multipart::from_request(request, &())
+        .await
+        .map_err(|_| ApiError::InvalidFile)?;
+    let mut inputs = Vec::new();
+    let mut id = None;
+    let mut fields = BulkFields::default();
+    while let Some(field) = form.next_field().await.map_err(|_| ApiError::InvalidFile)? {
+        let input = match field.name() {
+            Some("post_id") => {
+                let text = field.text().await.map_err(|_| ApiError::InvalidBatch)?;
+                id = Some(text.trim().parse().map_err(|_| ApiError::InvalidBatch)?);
+                continue;
+            }
+            Some(name @ ("tags" | "rating" | "timestamp" | "frames")) => {
+                let name = name.to_string();
+                let text = field.text().await.map_err(|_| ApiError::InvalidBatch)?;
+                match &name[..] {
+                    "tags" => fields.tags = Some(text),
+                    "rating" => fields.rating = Some(text),
+                    "timestamp" => {
+                        let timestamp = text.trim().parse().map_err(|_| ApiError::InvalidBatch)?;
+                        fields.timestamp = Some(timestamp);
+                    }
+                    _ => fields.frames = Some(text),
+                }
+                continue;
+            }
+            Some("file") => {
+                let bytes = field.bytes().await.map_err(|_| ApiError::InvalidFile)?;
+                SignatureInput::File(bytes)
+            }
+            Some("hash") => {
+                let text = field.text().await.map_err(|e| ApiError::InvalidHash {
+                    message: e.body_text(),
+                })?;
+                SignatureInput::Hash(text)
+            }
+            _ => return Err(ApiError::InvalidFile),
+        };
+        let metadata = std::mem::take(&mut fields).metadata();
+        inputs.push((id.take().ok_or(ApiError::InvalidBatch)?, (input, metadata)));
+    }
+    if inputs.is_empty() {
+        return Err(ApiError::MissingFileOrHash);
+    }
+    Ok(inputs)
+}
+
 pub async fn delete(
     Extension(sql_db): Extension<Arc<Mutex<SqlDB>>>,
     Extension(db): Extension<Arc<RwLock<DB>>>,
+    Extension(regions): Extension<Regions>,
     Path(id): Path<i64>,
 ) -> (StatusCode, Json<ApiResponse<DeleteImageResponse>>) {
     let mut db = db.write().await;
+    if db.is_read_only() {
+        return ApiResponse::err(ApiError::ReadOnly, StatusCode::FORBIDDEN);
+    }
 
     let image = {
         let mut sql_db = sql_db.lock().await;
@@ -92,7 +507,13 @@ pub async fn delete(
         }
     };
 
-    db.delete(image);
+    if let Err(e) = db.delete(image) {
+        return ApiResponse::err(e.into(), StatusCode::FORBIDDEN);
+    }
+    let metadata = Metadata::default();
+    if let Err(error) = update_regions(&regions, &sql_db, id, None, metadata).await {
+        return ApiResponse::err(error, StatusCode::INTERNAL_SERVER_ERROR);
+    }
 
     let response = DeleteImageResponse { id };
     ApiResponse::ok(response)
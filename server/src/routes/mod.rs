@@ -1,3 +1,4 @@
+pub mod admin;
 pub mod images;
 pub mod query;
 pub mod status;
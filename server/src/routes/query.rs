@@ -1,26 +1,89 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 
 use axum::{
-    extract::{Multipart, Query},
-    http::StatusCode,
+    body::Bytes,
+    extract::{FromRequest, Multipart, Query, Request},
+    http::{header::CONTENT_TYPE, StatusCode},
     Extension, Json,
 };
-use iqdb_rs::{Signature, DB};
+use iqdb_rs::{QueryOptions, Signature, WeightProfile, Weights, DB};
 use serde::{Deserialize, Serialize};
 use tokio::sync::{Mutex, RwLock};
 
-use crate::{response::SignatureResponse, utils::get_signature, ApiResponse, SqlDB};
+use crate::{
+    response::{BoundsResponse, SignatureResponse},
+    utils::{
+        compute_signatures, frame_selection, get_signatures, query_options, signature_options,
+        transforms, trimmed_file_signatures, FilterQuery, SignatureInput,
+    },
+    ApiError, ApiResponse, Config, Regions, SqlDB,
+};
 
 const fn query_default_limit() -> usize {
     20
 }
 
+/// Larger limits are lowered to this, for single queries and every batch item alike.
+const MAX_LIMIT: usize = 1000;
+
 #[derive(Deserialize)]
 pub struct GetQuery {
     #[serde(alias = "l", default = "query_default_limit")]
     pub limit: usize,
     #[serde(alias = "h")]
     pub hash: Option<String>,
+    pub alpha: Option<String>,
+    /// Trims borders within this tolerance before hashing, see [`iqdb_rs::trim_bounds`].
+    pub trim: Option<u8>,
+    pub frames: Option<String>,
+    /// Leaves out results scoring below this.
+    #[serde(alias = "s")]
+    pub min_score: Option<f32>,
+    /// `photo` or `sketch`, see [`iqdb_rs::WeightProfile`].
+    pub profile: Option<String>,
+    /// `color` or `grayscale`, see [`iqdb_rs::QueryMode`].
+    pub mode: Option<String>,
+    /// Also searches flipped and rotated copies of the query image, `all` or a comma
+    /// separated list of [`iqdb_rs::Transform`] names.
+    pub transforms: Option<String>,
+    /// Queries with the stored signature of an indexed post instead.
+    pub post_id: Option<i64>,
+    /// Leaves the `post_id` post itself out of the results.
+    #[serde(default)]
+    pub exclude_self: bool,
+    /// Also searches the region index, so that crops of indexed images are found.
+    #[serde(default)]
+    pub regions: bool,
+}
+
+#[derive(Deserialize)]
+pub struct BatchQuery {
+    #[serde(alias = "l", default = "query_default_limit")]
+    pub limit: usize,
+    pub alpha: Option<String>,
+    pub trim: Option<u8>,
+    #[serde(alias = "s")]
+    pub min_score: Option<f32>,
+    pub profile: Option<String>,
+    pub mode: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct BatchHash {
+    pub hash: String,
+    pub limit: Option<usize>,
+}
+
+/// The body of [`get`], either JSON or a multipart form with `file` and `weights` fields.
+#[derive(Default, Deserialize)]
+pub struct QueryBody {
+    #[serde(skip)]
+    pub file: Option<Bytes>,
+    pub hash: Option<String>,
+    pub weights: Option<Weights>,
 }
 
 pub type GetQueryResponse = Vec<GetQueryResponseImage>;
@@ -32,26 +95,310 @@ pub struct GetQueryResponseImage {
     pub score: f32,
     pub hash: String,
     pub signature: SignatureResponse,
+    /// The [`iqdb_rs::QueryMode`] the score was computed in.
+    pub mode: &'static str,
+    /// The [`iqdb_rs::WeightProfile`] the score was computed with, or `custom` for
+    /// weights given in the body.
+    pub profile: &'static str,
+    /// The transform of the query image that matched, when searching several.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transform: Option<&'static str>,
+    /// The part of the matching query frame that was hashed, when trimming borders.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trimmed: Option<BoundsResponse>,
 }
 
+pub type BatchQueryResponse = Vec<ApiResponse<GetQueryResponse>>;
+
 pub async fn get(
+    Extension(config): Extension<Config>,
     Extension(sql_db): Extension<Arc<Mutex<SqlDB>>>,
     Extension(db): Extension<Arc<RwLock<DB>>>,
-    Query(GetQuery { limit, hash }): Query<GetQuery>,
-    form: Option<Multipart>,
+    Extension(regions): Extension<Regions>,
+    Query(query): Query<GetQuery>,
+    Query(filter): Query<FilterQuery>,
+    request: Request,
 ) -> (StatusCode, Json<ApiResponse<GetQueryResponse>>) {
-    let looking_for = match get_signature(hash, form).await {
+    let limit = query.limit.min(MAX_LIMIT);
+    let options = match signature_options(query.alpha.as_deref(), query.trim, config.resampling) {
+        Ok(options) => options,
+        Err(error) => return ApiResponse::err(error, StatusCode::BAD_REQUEST),
+    };
+    let frames = match frame_selection(query.frames.as_deref()) {
+        Ok(frames) => frames,
+        Err(error) => return ApiResponse::err(error, StatusCode::BAD_REQUEST),
+    };
+    let filter = match filter.filter() {
+        Ok(filter) => filter,
+        Err(error) => return ApiResponse::err(error, StatusCode::BAD_REQUEST),
+    };
+    let body = match query_body(request).await {
+        Ok(body) => body,
+        Err(error) => return ApiResponse::err(error, StatusCode::BAD_REQUEST),
+    };
+    let transforms = match transforms(query.transforms.as_deref()) {
+        Ok(transforms) => transforms,
+        Err(error) => return ApiResponse::err(error, StatusCode::BAD_REQUEST),
+    };
+    let query_options = match query_options(
+        query.profile.as_deref(),
+        body.weights,
+        query.mode.as_deref(),
+        filter,
+        query.min_score,
+    ) {
+        Ok(query_options) => query_options,
+        Err(error) => return ApiResponse::err(error, StatusCode::BAD_REQUEST),
+    };
+    let regions = match (query.regions, regions.0) {
+        (false, _) => None,
+        (true, Some(regions)) => Some(regions),
+        (true, None) => {
+            return ApiResponse::err(ApiError::RegionsDisabled, StatusCode::BAD_REQUEST)
+        }
+    };
+    if let Some(id) = query.post_id {
+        // The sqlite db stays unlocked while the index is queried.
+        let indexed = db.read().await.contains(id);
+        let image = if indexed {
+            sql_db.lock().await.get_many([id]).next()
+        } else {
+            None
+        };
+        let Some(image) = image else {
+            return ApiResponse::err(ApiError::NotFound, StatusCode::NOT_FOUND);
+        };
+        let exclude_self = query.exclude_self;
+        let result = (db.read().await).query_image(&image, limit, &query_options, exclude_self);
+        let sql_db = sql_db.lock().await;
+        return ApiResponse::ok(response_images(&sql_db, &result, &query_options));
+    }
+    // Frames of an uploaded file come with the part of them that was hashed.
+    let looking_for = match (query.hash.or(body.hash), body.file) {
+        (None, Some(bytes)) => trimmed_file_signatures(&bytes, &options, frames).map(|sigs| {
+            let sigs = sigs.into_iter();
+            sigs.map(|(sig, bounds)| (sig, Some(bounds))).collect()
+        }),
+        (hash, _) => get_signatures(hash, None, &options, frames)
+            .await
+            .map(|sigs| sigs.into_iter().map(|sig| (sig, None)).collect::<Vec<_>>()),
+    };
+    let looking_for = match looking_for {
         Ok(s) => s,
         Err(error) => return ApiResponse::err(error, StatusCode::BAD_REQUEST),
     };
 
-    let result = {
+    // Each image keeps the best score of any of the queried frames and transforms, and
+    // of any of its regions. Regions are indexed under the id of their image.
+    let queries: Vec<_> = looking_for
+        .iter()
+        .flat_map(|(sig, bounds)| {
+            transforms
+                .iter()
+                .map(move |&t| (sig.transformed(t), t, *bounds))
+        })
+        .collect();
+    let mut result = Vec::new();
+    {
+        let db = db.read().await;
+        let regions = match &regions {
+            Some(regions) => Some(regions.read().await),
+            None => None,
+        };
+        for index in std::iter::once(&*db).chain(regions.as_deref()) {
+            for &(ref sig, transform, bounds) in &queries {
+                let scores = index.query_with(sig, limit, &query_options);
+                let scores = scores.into_iter();
+                result.extend(scores.map(|(score, id)| (score, id, transform, bounds)));
+            }
+        }
+    }
+    if queries.len() > 1 || regions.is_some() {
+        result.sort_by(|a, b| a.0.total_cmp(&b.0).then_with(|| a.1.cmp(&b.1)).reverse());
+        let mut seen = HashSet::new();
+        result.retain(|&(_, id, ..)| seen.insert(id));
+        result.truncate(limit);
+    }
+
+    let scores: Vec<_> = result.iter().map(|&(score, id, ..)| (score, id)).collect();
+    let mut images = {
+        let sql_db = sql_db.lock().await;
+        response_images(&sql_db, &scores, &query_options)
+    };
+    let matched: HashMap<_, _> = result.iter().map(|&(_, id, t, b)| (id, (t, b))).collect();
+    for image in &mut images {
+        let (transform, bounds) = matched[&image.id];
+        if query.transforms.is_some() {
+            image.transform = Some(transform.name());
+        }
+        if options.trim.is_some() {
+            image.trimmed = bounds;
+        }
+    }
+    ApiResponse::ok(images)
+}
+
+/// Runs many queries under a single read lock.
+///
+/// Takes either a multipart form of `file` fields, each optionally followed by a `limit`
+/// field, and `hashes` fields, or a JSON body. Hashes are given as a JSON array of
+/// `{"hash", "limit"}` objects. Results come back in input order.
+pub async fn batch(
+    Extension(config): Extension<Config>,
+    Extension(sql_db): Extension<Arc<Mutex<SqlDB>>>,
+    Extension(db): Extension<Arc<RwLock<DB>>>,
+    Query(query): Query<BatchQuery>,
+    Query(filter): Query<FilterQuery>,
+    request: Request,
+) -> (StatusCode, Json<ApiResponse<BatchQueryResponse>>) {
+    let options = match signature_options(query.alpha.as_deref(), query.trim, config.resampling) {
+        Ok(options) => options,
+        Err(error) => return ApiResponse::err(error, StatusCode::BAD_REQUEST),
+    };
+    let filter = match filter.filter() {
+        Ok(filter) => filter,
+        Err(error) => return ApiResponse::err(error, StatusCode::BAD_REQUEST),
+    };
+    let query_options = match query_options(
+        query.profile.as_deref(),
+        None,
+        query.mode.as_deref(),
+        filter,
+        query.min_score,
+    ) {
+        Ok(query_options) => query_options,
+        Err(error) => return ApiResponse::err(error, StatusCode::BAD_REQUEST),
+    };
+    let inputs = match batch_inputs(request).await {
+        Ok(inputs) => inputs,
+        Err(error) => return ApiResponse::err(error, StatusCode::BAD_REQUEST),
+    };
+
+    let (inputs, limits): (Vec<_>, Vec<_>) = inputs.into_iter().unzip();
+    let signatures = compute_signatures(inputs, &options).await;
+    let queries: Vec<_> = signatures
+        .into_iter()
+        .zip(limits)
+        .map(|(sig, limit)| sig.map(|sig| (sig, limit.unwrap_or(query.limit).min(MAX_LIMIT))))
+        .collect();
+
+    let mut results = {
+        let valid: Vec<_> = queries
+            .iter()
+            .filter_map(|q| q.as_ref().ok())
+            .cloned()
+            .collect();
         let db = db.read().await;
-        db.query(&looking_for, limit)
+        db.query_many(&valid, &query_options).into_iter()
     };
+    let sql_db = sql_db.lock().await;
+    let response = queries
+        .into_iter()
+        .map(|query| match query {
+            Ok(_) => ApiResponse::Ok(response_images(
+                &sql_db,
+                &results.next().unwrap(),
+                &query_options,
+            )),
+            Err(error) => ApiResponse::Err { error },
+        })
+        .collect();
+    ApiResponse::ok(response)
+}
+
+/// Reads a JSON or multipart body. Any other body is ignored, like a missing one.
+async fn query_body(request: Request) -> Result<QueryBody, ApiError> {
+    let content_type = request.headers().get(CONTENT_TYPE);
+    let content_type = content_type.and_then(|value| value.to_str().ok());
+    match content_type {
+        Some(value) if value.starts_with("application/json") => {
+            let Json(body) = Json::<QueryBody>::from_request(request, &())
+                .await
+                .map_err(|e| ApiError::InvalidBody {
+                    message: e.body_text(),
+                })?;
+            return Ok(body);
+        }
+        Some(value) if value.starts_with("multipart/form-data") => {}
+        _ => return Ok(QueryBody::default()),
+    }
+
+    let mut form = Multipart::from_request(request, &())
+        .await
+        .map_err(|_| ApiError::InvalidFile)?;
+    let mut body = QueryBody::default();
+    while let Some(field) = form.next_field().await.map_err(|_| ApiError::InvalidFile)? {
+        match field.name() {
+            Some("file") => {
+                body.file = Some(field.bytes().await.map_err(|_| ApiError::InvalidFile)?);
+            }
+            Some("weights") => {
+                let bytes = field.bytes().await.map_err(|_| ApiError::InvalidWeights)?;
+                let weights =
+                    serde_json::from_slice(&bytes).map_err(|_| ApiError::InvalidWeights)?;
+                body.weights = Some(weights);
+            }
+            _ => return Err(ApiError::InvalidFile),
+        }
+    }
+    Ok(body)
+}
 
+async fn batch_inputs(request: Request) -> Result<Vec<(SignatureInput, Option<usize>)>, ApiError> {
+    let content_type = request.headers().get(CONTENT_TYPE);
+    let content_type = content_type.and_then(|value| value.to_str().ok());
+    if !content_type.is_some_and(|value| value.starts_with("multipart/form-data")) {
+        let Json(hashes) = Json::<Vec<BatchHash>>::from_request(request, &())
+            .await
+            .map_err(|_| ApiError::InvalidBatch)?;
+        let inputs = hashes.into_iter();
+        return Ok(inputs
+            .map(|hash| (SignatureInput::Hash(hash.hash), hash.limit))
+            .collect());
+    }
+
+    let mut form = Multipart::from_request(request, &())
+        .await
+        .map_err(|_| ApiError::InvalidFile)?;
+    let mut inputs = Vec::new();
+    while let Some(field) = form.next_field().await.map_err(|_| ApiError::InvalidFile)? {
+        match field.name() {
+            Some("file") => {
+                let bytes = field.bytes().await.map_err(|_| ApiError::InvalidFile)?;
+                inputs.push((SignatureInput::File(bytes), None));
+            }
+            Some("limit") => {
+                let text = field.text().await.map_err(|_| ApiError::InvalidBatch)?;
+                let limit = text.trim().parse().map_err(|_| ApiError::InvalidBatch)?;
+                let (_, last) = inputs.last_mut().ok_or(ApiError::InvalidBatch)?;
+                *last = Some(limit);
+            }
+            Some("hashes") => {
+                let bytes = field.bytes().await.map_err(|_| ApiError::InvalidBatch)?;
+                let hashes: Vec<BatchHash> =
+                    serde_json::from_slice(&bytes).map_err(|_| ApiError::InvalidBatch)?;
+                inputs.extend(
+                    hashes
+                        .into_iter()
+                        .map(|hash| (SignatureInput::Hash(hash.hash), hash.limit)),
+                );
+            }
+            _ => return Err(ApiError::InvalidFile),
+        }
+    }
+    if inputs.is_empty() {
+        return Err(ApiError::MissingFileOrHash);
+    }
+    Ok(inputs)
+}
+
+fn response_images(
+    sql_db: &SqlDB,
+    result: &[(f32, i64)],
+    options: &QueryOptions,
+) -> GetQueryResponse {
+    let profile = WeightProfile::of(&options.weights).map_or("custom", WeightProfile::name);
     let images: Vec<_> = {
-        let sql_db = sql_db.lock().await;
         let ids = result.iter().map(|(_, i)| *i);
         sql_db.get_many(ids).collect()
     };
@@ -77,6 +424,10 @@ pub async fn get(
                     avglf: sig.avgl,
                     sig: sig.sig,
                 },
+                mode: options.mode.name(),
+                profile,
+                transform: None,
+                trimmed: None,
             }
         })
         .collect();
@@ -86,6 +437,5 @@ pub async fn get(
             .then_with(|| a.id.cmp(&b.id))
             .reverse()
     });
-
-    ApiResponse::ok(images)
+    images
 }
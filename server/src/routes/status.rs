@@ -10,16 +10,26 @@ use crate::ApiResponse;
 #[derive(Serialize)]
 pub struct GetStatusResponse {
     pub images: u32,
+    pub frames: u32,
+    pub deleted: u32,
+    pub kernel: &'static str,
 }
 
 pub async fn get(
     Extension(db): Extension<Arc<RwLock<DB>>>,
 ) -> (StatusCode, Json<ApiResponse<GetStatusResponse>>) {
-    let images = {
+    let (images, frames, deleted, kernel) = {
         let db = db.read().await;
-        db.image_count() as u32
+        let frames = db.frame_count() as u32;
+        let deleted = db.deleted_count() as u32;
+        (db.image_count() as u32, frames, deleted, db.kernel().name())
     };
 
-    let response = GetStatusResponse { images };
+    let response = GetStatusResponse {
+        images,
+        frames,
+        deleted,
+        kernel,
+    };
     ApiResponse::ok(response)
 }
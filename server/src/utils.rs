@@ -1,26 +1,262 @@
-use axum::extract::Multipart;
-use iqdb_rs::Signature;
+use axum::{body::Bytes, extract::Multipart};
+use image::DynamicImage;
+use iqdb_rs::{
+    Filter, FrameSelection, QueryOptions, Resampling, Signature, SignatureError, SignatureOptions,
+    Transform, WeightProfile, Weights,
+};
+use serde::Deserialize;
 
-use crate::ApiError;
+use crate::{response::BoundsResponse, ApiError};
 
-pub async fn get_signature(
+pub fn signature_options(
+    alpha: Option<&str>,
+    trim: Option<u8>,
+    resampling: Resampling,
+) -> Result<SignatureOptions, ApiError> {
+    let mut options = SignatureOptions {
+        trim,
+        resampling,
+        ..Default::default()
+    };
+    if let Some(alpha) = alpha {
+        options.alpha = alpha.parse().map_err(|_| ApiError::InvalidAlpha)?;
+    }
+    Ok(options)
+}
+
+/// Picks the weights of a named profile, or custom ones, which take precedence.
+pub fn query_options(
+    profile: Option<&str>,
+    weights: Option<Weights>,
+    mode: Option<&str>,
+    filter: Filter,
+    min_score: Option<f32>,
+) -> Result<QueryOptions, ApiError> {
+    let mut options = QueryOptions {
+        filter,
+        min_score,
+        ..Default::default()
+    };
+    if let Some(mode) = mode {
+        options.mode = mode.parse().map_err(|_| ApiError::InvalidMode {
+            message: format!("unknown mode {mode:?}, expected \"color\" or \"grayscale\""),
+        })?;
+    }
+    if let Some(profile) = profile {
+        let profile: WeightProfile = profile.parse().map_err(|_| ApiError::InvalidProfile {
+            message: format!("unknown profile {profile:?}, expected \"photo\" or \"sketch\""),
+        })?;
+        options.weights = profile.weights();
+    }
+    if let Some(weights) = weights {
+        if !weights.iter().flatten().all(|w| w.is_finite() && *w >= 0.) {
+            return Err(ApiError::InvalidWeights);
+        }
+        options.weights = weights;
+    }
+    Ok(options)
+}
+
+/// Parses `all` or a comma separated list of transforms. The untransformed
+/// image is always searched.
+pub fn transforms(transforms: Option<&str>) -> Result<Vec<Transform>, ApiError> {
+    let mut parsed = vec![Transform::None];
+    match transforms {
+        None => {}
+        Some("all") => parsed = Transform::ALL.to_vec(),
+        Some(transforms) => {
+            for transform in transforms.split(',').filter(|t| !t.is_empty()) {
+                let transform = transform.parse().map_err(|_| ApiError::InvalidTransform)?;
+                if !parsed.contains(&transform) {
+                    parsed.push(transform);
+                }
+            }
+        }
+    }
+    Ok(parsed)
+}
+
+/// The most frames hashed for a single animation.
+pub const MAX_FRAMES: usize = 16;
+
+pub fn frame_selection(frames: Option<&str>) -> Result<FrameSelection, ApiError> {
+    let Some(frames) = frames else {
+        return Ok(FrameSelection::First);
+    };
+    match frames.parse() {
+        Ok(FrameSelection::Spaced(n)) if n > MAX_FRAMES => Err(ApiError::InvalidFrames),
+        Ok(selection) => Ok(selection),
+        Err(()) => Err(ApiError::InvalidFrames),
+    }
+}
+
+/// Parses comma separated tags between 0 and 63 into a bitmask.
+pub fn parse_tags(tags: Option<&str>) -> Result<u64, ApiError> {
+    let mut mask = 0;
+    for tag in tags
+        .unwrap_or_default()
+        .split(',')
+        .filter(|t| !t.is_empty())
+    {
+        match tag.parse::<u32>() {
+            Ok(tag) if tag < u64::BITS => mask |= 1 << tag,
+            _ => return Err(ApiError::InvalidMetadata),
+        }
+    }
+    Ok(mask)
+}
+
+/// Parses comma separated ratings, each a single ASCII character such as `s`.
+pub fn parse_ratings(ratings: Option<&str>) -> Result<Vec<u8>, ApiError> {
+    let ratings = ratings
+        .unwrap_or_default()
+        .split(',')
+        .filter(|r| !r.is_empty());
+    ratings
+        .map(|rating| match rating.as_bytes() {
+            &[rating] if rating.is_ascii() => Ok(rating),
+            _ => Err(ApiError::InvalidMetadata),
+        })
+        .collect()
+}
+
+/// Query parameters restricting results by metadata.
+#[derive(Deserialize)]
+pub struct FilterQuery {
+    pub tags: Option<String>,
+    pub exclude_tags: Option<String>,
+    pub rating: Option<String>,
+    pub after: Option<i64>,
+    pub before: Option<i64>,
+}
+
+impl FilterQuery {
+    pub fn filter(&self) -> Result<Filter, ApiError> {
+        Ok(Filter {
+            tags: parse_tags(self.tags.as_deref())?,
+            exclude_tags: parse_tags(self.exclude_tags.as_deref())?,
+            ratings: parse_ratings(self.rating.as_deref())?,
+            after: self.after,
+            before: self.before,
+        })
+    }
+}
+
+pub enum SignatureInput {
+    File(Bytes),
+    Hash(String),
+}
+
+/// Hashes many inputs in parallel on the blocking thread pool, keeping their order.
+pub async fn compute_signatures(
+    inputs: Vec<SignatureInput>,
+    options: &SignatureOptions,
+) -> Vec<Result<Signature, ApiError>> {
+    let tasks: Vec<_> = inputs
+        .into_iter()
+        .map(|input| {
+            let options = options.clone();
+            tokio::task::spawn_blocking(move || match input {
+                SignatureInput::Hash(hash) => Ok(hash.parse()?),
+                SignatureInput::File(bytes) => {
+                    let sigs = file_signatures(&bytes, &options, FrameSelection::First)?;
+                    sigs.into_iter().next().ok_or(ApiError::InvalidImage {
+                        message: "image has no frames".to_string(),
+                    })
+                }
+            })
+        })
+        .collect();
+    let mut signatures = Vec::with_capacity(tasks.len());
+    for task in tasks {
+        // A task only fails if hashing panicked, which a malformed image can cause.
+        signatures.push(task.await.unwrap_or_else(|error| {
+            Err(ApiError::InvalidImage {
+                message: error.to_string(),
+            })
+        }));
+    }
+    signatures
+}
+
+/// Hashes the selected frames of an uploaded file.
+pub fn file_signatures(
+    bytes: &[u8],
+    options: &SignatureOptions,
+    frames: FrameSelection,
+) -> Result<Vec<Signature>, ApiError> {
+    let signatures = trimmed_file_signatures(bytes, options, frames)?;
+    Ok(signatures.into_iter().map(|(sig, _)| sig).collect())
+}
+
+/// Hashes the selected frames of an uploaded file, with the part of each frame that
+/// was left after trimming borders.
+pub fn trimmed_file_signatures(
+    bytes: &[u8],
+    options: &SignatureOptions,
+    frames: FrameSelection,
+) -> Result<Vec<(Signature, BoundsResponse)>, ApiError> {
+    let (frames, size) = decode_file(bytes, frames)?;
+    Ok(frame_signatures(&frames, size, options))
+}
+
+/// Decodes the selected frames of an uploaded file, along with its full size.
+///
+/// Large JPEGs are scaled down while decoding, see [`iqdb_rs::decode_frames_scaled`].
+pub fn decode_file(
+    bytes: &[u8],
+    frames: FrameSelection,
+) -> Result<(Vec<DynamicImage>, (u32, u32)), ApiError> {
+    let decoded = iqdb_rs::decode_frames_scaled(bytes, frames, iqdb_rs::MIN_DECODE_SIZE)
+        .map_err(SignatureError::from)?;
+    Ok(decoded)
+}
+
+/// Hashes decoded frames, with the part of each frame that was left after trimming
+/// borders, scaled back up to the full `(width, height)` of the file.
+pub fn frame_signatures(
+    frames: &[DynamicImage],
+    (width, height): (u32, u32),
+    options: &SignatureOptions,
+) -> Vec<(Signature, BoundsResponse)> {
+    frames
+        .iter()
+        .map(|img| {
+            let (sig, (x, y, w, h)) = Signature::from_image_trimmed(img, options);
+            let scale_x = |x: u32| (x as u64 * width as u64 / img.width().max(1) as u64) as u32;
+            let scale_y = |y: u32| (y as u64 * height as u64 / img.height().max(1) as u64) as u32;
+            let (x, y, right, bottom) = (scale_x(x), scale_y(y), scale_x(x + w), scale_y(y + h));
+            (sig, (x, y, right - x, bottom - y).into())
+        })
+        .collect()
+}
+
+/// Hashes the selected frames of the uploaded file, or parses a single hash.
+pub async fn get_signatures(
     hash: Option<String>,
     form: Option<Multipart>,
-) -> Result<Signature, ApiError> {
+    options: &SignatureOptions,
+    frames: FrameSelection,
+) -> Result<Vec<Signature>, ApiError> {
     if let Some(hash) = hash {
-        hash.parse().map_err(|_| ApiError::InvalidHash)
-    } else if let Some(mut form) = form {
-        let maybe_field = form.next_field().await.map_err(|_| ApiError::InvalidFile)?;
-        let Some(field) = maybe_field else {
-            return Err(ApiError::MissingFileOrHash);
-        };
-        if field.name() != Some("file") {
-            return Err(ApiError::InvalidFile);
-        }
-        let bytes = field.bytes().await.map_err(|_| ApiError::InvalidFile)?;
-        let img = image::load_from_memory(&bytes).map_err(|_| ApiError::InvalidImage)?;
-        Ok(Signature::from_image(&img))
+        let sig = hash.parse()?;
+        Ok(vec![sig])
+    } else if let Some(form) = form {
+        let bytes = form_file(form).await?;
+        file_signatures(&bytes, options, frames)
     } else {
         Err(ApiError::MissingFileOrHash)
     }
 }
+
+/// Reads the `file` field, which must come first in the form.
+pub async fn form_file(mut form: Multipart) -> Result<Bytes, ApiError> {
+    let maybe_field = form.next_field().await.map_err(|_| ApiError::InvalidFile)?;
+    let Some(field) = maybe_field else {
+        return Err(ApiError::MissingFileOrHash);
+    };
+    if field.name() != Some("file") {
+        return Err(ApiError::InvalidFile);
+    }
+    field.bytes().await.map_err(|_| ApiError::InvalidFile)
+}